@@ -0,0 +1,107 @@
+//! Async `Stream` over generation events
+//!
+//! Candle forward passes are blocking, so the generation runs on a dedicated
+//! worker thread and its events are forwarded through a bounded channel.
+//! Dropping the stream closes the channel, which stops the worker after the
+//! forward pass it is currently running.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, Stream};
+
+use crate::generation::{GenerationEvent, GenerationStream, TextGeneration};
+use crate::generation_config::GenerationRequest;
+
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Prompt size and timings of a finished run
+#[derive(Debug, Clone, Copy)]
+pub struct StreamStats {
+    pub prompt_tokens: usize,
+    pub prompt_duration: Duration,
+    pub generation_duration: Duration,
+}
+
+/// Stream of generation events produced on a worker thread
+pub struct GenerationEventStream {
+    receiver: mpsc::Receiver<Result<GenerationEvent>>,
+    stats: Arc<Mutex<Option<StreamStats>>>,
+}
+
+impl GenerationEventStream {
+    /// Statistics of the run, available once the event with a `finish_reason` is received
+    pub fn stats(&self) -> Option<StreamStats> {
+        *self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Stream for GenerationEventStream {
+    type Item = Result<GenerationEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// Run `prompt` on a worker thread and expose its events as a `Stream`.
+///
+/// The worker holds the lock on `generation` until the run finishes or the
+/// stream is dropped, so concurrent requests on the same model are queued.
+pub fn generate_stream(
+    generation: Arc<Mutex<TextGeneration>>,
+    prompt: impl Into<String>,
+    request: GenerationRequest,
+) -> GenerationEventStream {
+    let prompt = prompt.into();
+    spawn_stream(generation, move |generation| {
+        generation.stream(&prompt, &request)
+    })
+}
+
+/// Like `generate_stream`, but `start` creates the `GenerationStream` on the worker
+/// while the lock is held, e.g. to use `stream_raw` on an already formatted prompt.
+pub fn spawn_stream<F>(generation: Arc<Mutex<TextGeneration>>, start: F) -> GenerationEventStream
+where
+    F: FnOnce(&mut TextGeneration) -> Result<GenerationStream<'_>> + Send + 'static,
+{
+    let (mut sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let stats = Arc::new(Mutex::new(None));
+    let worker_stats = stats.clone();
+
+    std::thread::spawn(move || {
+        let mut generation = generation.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stream = match start(&mut generation) {
+            Ok(stream) => stream,
+            Err(err) => {
+                let _ = block_on(sender.send(Err(err)));
+                return;
+            }
+        };
+        while let Some(event) = stream.next() {
+            // Recorded before the final event is sent, so the receiver can read it
+            if let Ok(GenerationEvent {
+                finish_reason: Some(_),
+                ..
+            }) = &event
+            {
+                *worker_stats.lock().unwrap_or_else(PoisonError::into_inner) = Some(StreamStats {
+                    prompt_tokens: stream.prompt_tokens(),
+                    prompt_duration: stream.prompt_duration(),
+                    generation_duration: stream.generation_duration(),
+                });
+            }
+            if block_on(sender.send(event)).is_err() {
+                // Receiver dropped: the caller is no longer interested
+                break;
+            }
+        }
+    });
+
+    GenerationEventStream { receiver, stats }
+}
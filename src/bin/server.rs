@@ -0,0 +1,33 @@
+//! OpenAI- and Ollama-compatible HTTP server
+//!
+//! Usage: `llm-rs-server [config.toml]`
+//!
+//! Without a config file, `llm-rs.toml` is used if present; `LLM_RS_*`
+//! environment variables override either, see `llm_rs::config`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use llm_rs::config::Config;
+use llm_rs::hub::resolve_model;
+use llm_rs::server::{AppState, serve};
+use llm_rs::setup_with_header;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config_path = std::env::args().nth(1).map(PathBuf::from);
+    let config = Config::load(config_path.as_deref())?;
+
+    let model_name = Path::new(&config.model)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "llm-rs".to_string());
+    let interrupt_signal = Arc::new(AtomicBool::new(false));
+    let (generation, gguf) = setup_with_header(config.model_args(interrupt_signal))?;
+    let model_path = resolve_model(&config.model, config.revision.as_deref())?;
+    let state = AppState::new(generation, model_name).with_model_file(model_path, gguf.as_ref())?;
+
+    println!("Listening on http://{}", config.address);
+    serve(&config.address, state).await
+}
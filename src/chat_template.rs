@@ -7,7 +7,7 @@
 //!
 //! ```no_run
 //! # fn main() -> Result<(), Box<dyn std::error::Error>> {
-//! use candle_examples::chat_template::{ChatTemplate, ChatTemplateOptions, Message, Conversation};
+//! use llm_rs::chat_template::{ChatTemplate, Message, Conversation};
 //!
 //! // Load template from a model's tokenizer_config.json
 //! let template = ChatTemplate::from_tokenizer_config("path/to/tokenizer_config.json")?;
@@ -32,6 +32,9 @@
 //! # }
 //! ```
 
+use std::path::Path;
+
+use candle_core::quantized::gguf_file;
 use minijinja::{Environment, context};
 use serde::{Deserialize, Serialize};
 
@@ -58,9 +61,9 @@ impl Message {
         Self::new("user", content)
     }
 
-    // pub fn assistant(content: impl Into<String>) -> Self {
-    //     Self::new("assistant", content)
-    // }
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new("assistant", content)
+    }
 }
 
 /// Options for applying a chat template
@@ -84,72 +87,94 @@ impl ChatTemplateOptions {
         }
     }
 
-    // pub fn for_training() -> Self {
-    //     Self {
-    //         add_generation_prompt: false,
-    //         ..Default::default()
-    //     }
-    // }
+    pub fn for_training() -> Self {
+        Self {
+            add_generation_prompt: false,
+            ..Default::default()
+        }
+    }
 
-    // pub fn with_thinking(mut self) -> Self {
-    //     self.enable_thinking = true;
-    //     self
-    // }
+    pub fn with_thinking(mut self) -> Self {
+        self.enable_thinking = true;
+        self
+    }
 }
 
 /// Token configuration loaded from tokenizer_config.json
-// #[derive(Debug, Clone, Default, Deserialize)]
-// pub struct TokenConfig {
-//     #[serde(default)]
-//     pub bos_token: Option<StringOrToken>,
-//     #[serde(default)]
-//     pub eos_token: Option<StringOrToken>,
-//     #[serde(default)]
-//     pub unk_token: Option<StringOrToken>,
-//     #[serde(default)]
-//     pub pad_token: Option<StringOrToken>,
-//     #[serde(default)]
-//     pub chat_template: Option<ChatTemplateConfig>,
-// }
-
-// /// Handle both string and object token formats in tokenizer_config.json
-// #[derive(Debug, Clone, Deserialize)]
-// #[serde(untagged)]
-// pub enum StringOrToken {
-//     String(String),
-//     Token { content: String },
-// }
-
-// impl StringOrToken {
-//     pub fn as_str(&self) -> &str {
-//         match self {
-//             StringOrToken::String(s) => s,
-//             StringOrToken::Token { content } => content,
-//         }
-//     }
-// }
-
-// impl Default for StringOrToken {
-//     fn default() -> Self {
-//         StringOrToken::String(String::new())
-//     }
-// }
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TokenConfig {
+    #[serde(default)]
+    pub bos_token: Option<StringOrToken>,
+    #[serde(default)]
+    pub eos_token: Option<StringOrToken>,
+    #[serde(default)]
+    pub unk_token: Option<StringOrToken>,
+    #[serde(default)]
+    pub pad_token: Option<StringOrToken>,
+    #[serde(default)]
+    pub chat_template: Option<ChatTemplateConfig>,
+}
+
+/// Handle both string and object token formats in tokenizer_config.json
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum StringOrToken {
+    String(String),
+    Token { content: String },
+}
+
+impl StringOrToken {
+    pub fn as_str(&self) -> &str {
+        match self {
+            StringOrToken::String(s) => s,
+            StringOrToken::Token { content } => content,
+        }
+    }
+}
+
+impl Default for StringOrToken {
+    fn default() -> Self {
+        StringOrToken::String(String::new())
+    }
+}
 
 /// Chat template can be a single string or multiple named templates
-// #[derive(Debug, Clone, Deserialize)]
-// #[serde(untagged)]
-// pub enum ChatTemplateConfig {
-//     Single(String),
-//     Multiple(Vec<NamedTemplate>),
-// }
-
-// #[derive(Debug, Clone, Deserialize)]
-// pub struct NamedTemplate {
-//     pub name: String,
-//     pub template: String,
-// }
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ChatTemplateConfig {
+    Single(String),
+    Multiple(Vec<NamedTemplate>),
+}
+
+impl ChatTemplateConfig {
+    /// Select a template by name; `None` picks "default", or the first one if there is no default
+    pub fn select(&self, name: Option<&str>) -> Result<&str, ChatTemplateError> {
+        match self {
+            ChatTemplateConfig::Single(template) => match name {
+                None | Some("default") => Ok(template),
+                Some(_) => Err(ChatTemplateError::NoTemplate),
+            },
+            ChatTemplateConfig::Multiple(templates) => {
+                let wanted = name.unwrap_or("default");
+                templates
+                    .iter()
+                    .find(|t| t.name == wanted)
+                    .or_else(|| templates.first().filter(|_| name.is_none()))
+                    .map(|t| t.template.as_str())
+                    .ok_or(ChatTemplateError::NoTemplate)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NamedTemplate {
+    pub name: String,
+    pub template: String,
+}
 
 /// Chat template renderer using MiniJinja
+#[derive(Clone)]
 pub struct ChatTemplate {
     env: Environment<'static>,
     bos_token: String,
@@ -164,6 +189,8 @@ impl ChatTemplate {
         eos_token: impl Into<String>,
     ) -> Result<Self, ChatTemplateError> {
         let mut env = Environment::new();
+        // HF templates are written for Jinja2 in Python and call str/dict methods
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
         // Add the raise_exception function that HF templates use
         env.add_function("raise_exception", |msg: String| -> Result<String, _> {
             Err(minijinja::Error::new(
@@ -171,6 +198,10 @@ impl ChatTemplate {
                 msg,
             ))
         });
+        // Used by Llama 3 and SmolLM3 templates to insert the current date
+        env.add_function("strftime_now", |format: String| {
+            chrono::Local::now().format(&format).to_string()
+        });
 
         env.add_template_owned("chat".to_string(), template.into())
             .map_err(|e| ChatTemplateError::TemplateError(e.to_string()))?;
@@ -182,6 +213,79 @@ impl ChatTemplate {
         })
     }
 
+    /// Load the chat template from a model's tokenizer_config.json
+    pub fn from_tokenizer_config(path: impl AsRef<Path>) -> Result<Self, ChatTemplateError> {
+        Self::from_tokenizer_config_named(path, None)
+    }
+
+    /// Load a named template (e.g. "tool_use") from tokenizer_config.json
+    pub fn from_tokenizer_config_named(
+        path: impl AsRef<Path>,
+        name: Option<&str>,
+    ) -> Result<Self, ChatTemplateError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ChatTemplateError::IoError(e.to_string()))?;
+        let config: TokenConfig = serde_json::from_str(&content)
+            .map_err(|e| ChatTemplateError::ParseError(e.to_string()))?;
+        Self::from_token_config(&config, name)
+    }
+
+    /// Create from an already parsed tokenizer_config.json
+    pub fn from_token_config(
+        config: &TokenConfig,
+        name: Option<&str>,
+    ) -> Result<Self, ChatTemplateError> {
+        let template = config
+            .chat_template
+            .as_ref()
+            .ok_or(ChatTemplateError::NoTemplate)?
+            .select(name)?;
+        let bos_token = config.bos_token.as_ref().map_or("", |t| t.as_str());
+        let eos_token = config.eos_token.as_ref().map_or("", |t| t.as_str());
+        Self::new(template, bos_token, eos_token)
+    }
+
+    /// Load the chat template embedded in a GGUF header.
+    ///
+    /// The default template is stored under `tokenizer.chat_template`, named
+    /// ones under `tokenizer.chat_template.<name>`.
+    pub fn from_gguf(
+        content: &gguf_file::Content,
+        name: Option<&str>,
+    ) -> Result<Self, ChatTemplateError> {
+        let key = match name {
+            None | Some("default") => "tokenizer.chat_template".to_string(),
+            Some(name) => format!("tokenizer.chat_template.{name}"),
+        };
+        let template = content
+            .metadata
+            .get(&key)
+            .and_then(|v| v.to_string().ok())
+            .ok_or(ChatTemplateError::NoTemplate)?;
+
+        let tokens = content
+            .metadata
+            .get("tokenizer.ggml.tokens")
+            .and_then(|v| v.to_vec().ok());
+        let token_str = |key: &str| -> String {
+            content
+                .metadata
+                .get(key)
+                .and_then(|v| v.to_u32().ok())
+                .zip(tokens)
+                .and_then(|(id, tokens)| tokens.get(id as usize))
+                .and_then(|v| v.to_string().ok())
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        Self::new(
+            template.as_str(),
+            token_str("tokenizer.ggml.bos_token_id"),
+            token_str("tokenizer.ggml.eos_token_id"),
+        )
+    }
+
     /// ChatML template used by SmolLM, Qwen, and many other models
     pub fn chatml() -> Self {
         let template = r#"
@@ -196,21 +300,27 @@ impl ChatTemplate {
     }
 
     /// ChatML template with thinking/reasoning support
-    //     pub fn chatml_with_thinking() -> Self {
-    //         let template = r#"
-    // {%- for message in messages %}
-    // {{- '<|im_start|>' + message.role + '\n' + message.content | trim + '<|im_end|>\n' }}
-    // {%- endfor %}
-    // {%- if add_generation_prompt %}
-    // {%- if enable_thinking %}
-    // {{- '<|im_start|>assistant\n<think>\n' }}
-    // {%- else %}
-    // {{- '<|im_start|>assistant\n' }}
-    // {%- endif %}
-    // {%- endif %}
-    // "#;
-    //         Self::new(template, "", "<|im_end|>").unwrap()
-    //     }
+    pub fn chatml_with_thinking() -> Self {
+        let template = r#"
+{%- for message in messages %}
+{{- '<|im_start|>' + message.role + '\n' + message.content | trim + '<|im_end|>\n' }}
+{%- endfor %}
+{%- if add_generation_prompt %}
+{%- if enable_thinking %}
+{{- '<|im_start|>assistant\n<think>\n' }}
+{%- else %}
+{{- '<|im_start|>assistant\n' }}
+{%- endif %}
+{%- endif %}
+"#;
+        Self::new(template, "", "<|im_end|>").unwrap()
+    }
+
+    /// Token that closes a message, e.g. `<|im_end|>` or `<|eot_id|>`
+    pub fn eos_token(&self) -> &str {
+        &self.eos_token
+    }
+
     /// Apply the chat template to messages
     pub fn apply(
         &self,
@@ -236,103 +346,110 @@ impl ChatTemplate {
         Ok(result.trim_start().to_string())
     }
 
-    // Convenience method: apply with add_generation_prompt=true
-    // pub fn apply_for_generation(&self, messages: &[Message]) -> Result<String, ChatTemplateError> {
-    //     self.apply(messages, &ChatTemplateOptions::for_generation())
-    // }
+    /// Convenience method: apply with add_generation_prompt=true
+    pub fn apply_for_generation(&self, messages: &[Message]) -> Result<String, ChatTemplateError> {
+        self.apply(messages, &ChatTemplateOptions::for_generation())
+    }
 }
 
 /// Multi-turn conversation manager
-// pub struct Conversation {
-//     messages: Vec<Message>,
-//     template: ChatTemplate,
-//     options: ChatTemplateOptions,
-// }
-
-// impl Conversation {
-//     /// Create a new conversation with a system prompt
-//     pub fn new(template: ChatTemplate, system_prompt: impl Into<String>) -> Self {
-//         Self {
-//             messages: vec![Message::system(system_prompt)],
-//             template,
-//             options: ChatTemplateOptions::for_generation(),
-//         }
-//     }
-
-//     /// Create without a system prompt
-//     pub fn without_system(template: ChatTemplate) -> Self {
-//         Self {
-//             messages: Vec::new(),
-//             template,
-//             options: ChatTemplateOptions::for_generation(),
-//         }
-//     }
-
-//     /// Set options (e.g., enable thinking mode)
-//     pub fn with_options(mut self, options: ChatTemplateOptions) -> Self {
-//         self.options = options;
-//         self
-//     }
-
-//     /// Add a user message and return the formatted prompt for generation
-//     pub fn user_turn(&mut self, content: impl Into<String>) -> Result<String, ChatTemplateError> {
-//         self.messages.push(Message::user(content));
-//         self.template.apply(&self.messages, &self.options)
-//     }
-
-//     /// Record the assistant's response after generation
-//     pub fn assistant_response(&mut self, content: impl Into<String>) {
-//         self.messages.push(Message::assistant(content));
-//     }
-
-//     /// Add a message with a custom role
-//     pub fn add_message(&mut self, message: Message) {
-//         self.messages.push(message);
-//     }
-
-//     /// Get the conversation history
-//     pub fn messages(&self) -> &[Message] {
-//         &self.messages
-//     }
-
-//     /// Clear conversation history (keeps system prompt if present)
-//     pub fn clear(&mut self) {
-//         if let Some(first) = self.messages.first() {
-//             if first.role == "system" {
-//                 let system = self.messages.remove(0);
-//                 self.messages.clear();
-//                 self.messages.push(system);
-//                 return;
-//             }
-//         }
-//         self.messages.clear();
-//     }
-
-//     /// Format entire conversation for display (no generation prompt)
-//     pub fn format_history(&self) -> Result<String, ChatTemplateError> {
-//         self.template
-//             .apply(&self.messages, &ChatTemplateOptions::for_training())
-//     }
-// }
+pub struct Conversation {
+    messages: Vec<Message>,
+    template: ChatTemplate,
+    options: ChatTemplateOptions,
+}
+
+impl Conversation {
+    /// Create a new conversation with a system prompt
+    pub fn new(template: ChatTemplate, system_prompt: impl Into<String>) -> Self {
+        Self {
+            messages: vec![Message::system(system_prompt)],
+            template,
+            options: ChatTemplateOptions::for_generation(),
+        }
+    }
+
+    /// Create without a system prompt
+    pub fn without_system(template: ChatTemplate) -> Self {
+        Self {
+            messages: Vec::new(),
+            template,
+            options: ChatTemplateOptions::for_generation(),
+        }
+    }
+
+    /// Set options (e.g., enable thinking mode)
+    pub fn with_options(mut self, options: ChatTemplateOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Add a user message and return the formatted prompt for generation
+    pub fn user_turn(&mut self, content: impl Into<String>) -> Result<String, ChatTemplateError> {
+        self.messages.push(Message::user(content));
+        let prompt = self.template.apply(&self.messages, &self.options);
+        if prompt.is_err() {
+            self.messages.pop();
+        }
+        prompt
+    }
+
+    /// Format the conversation as it is, e.g. after adding messages with `add_message`
+    pub fn prompt(&self) -> Result<String, ChatTemplateError> {
+        self.template.apply(&self.messages, &self.options)
+    }
+
+    /// Record the assistant's response after generation
+    pub fn assistant_response(&mut self, content: impl Into<String>) {
+        self.messages.push(Message::assistant(content));
+    }
+
+    /// Remove and return the last message, e.g. a user turn whose reply failed
+    pub fn pop_message(&mut self) -> Option<Message> {
+        self.messages.pop()
+    }
+
+    /// Add a message with a custom role
+    pub fn add_message(&mut self, message: Message) {
+        self.messages.push(message);
+    }
+
+    /// Get the conversation history
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Clear conversation history (keeps system prompt if present)
+    pub fn clear(&mut self) {
+        let keep = usize::from(self.messages.first().is_some_and(|m| m.role == "system"));
+        self.messages.truncate(keep);
+    }
+
+    /// Format entire conversation for display (no generation prompt)
+    pub fn format_history(&self) -> Result<String, ChatTemplateError> {
+        self.template
+            .apply(&self.messages, &ChatTemplateOptions::for_training())
+    }
+}
 
 /// Errors that can occur with chat templates
 #[derive(Debug)]
 pub enum ChatTemplateError {
-    // IoError(String),
-    // ParseError(String),
+    IoError(String),
+    ParseError(String),
     TemplateError(String),
     RenderError(String),
-    // NoTemplate,
+    NoTemplate,
 }
 
 impl std::fmt::Display for ChatTemplateError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            // Self::IoError(e) => write!(f, "IO error: {}", e),
-            // Self::ParseError(e) => write!(f, "Parse error: {}", e),
+            Self::IoError(e) => write!(f, "IO error: {}", e),
+            Self::ParseError(e) => write!(f, "Parse error: {}", e),
             Self::TemplateError(e) => write!(f, "Template error: {}", e),
             Self::RenderError(e) => write!(f, "Render error: {}", e),
-            // Self::NoTemplate => write!(f, "No chat_template found in config"),
+            Self::NoTemplate => write!(f, "No chat_template found in config"),
         }
     }
 }
@@ -0,0 +1,263 @@
+//! Runtime configuration
+//!
+//! Settings come from, in increasing priority: built-in defaults, a TOML or
+//! JSON config file, `LLM_RS_*` environment variables, and command-line
+//! flags applied by the binaries. Generation settings are the fields of
+//! `GenerationConfig`, written at the top level. Example `llm-rs.toml`:
+//!
+//! ```toml
+//! model = "model/llm.gguf"
+//! device = "cuda:0"
+//! max_tokens = 512
+//! enable_thinking = true
+//!
+//! [sampling]
+//! strategy = "top_p"
+//! p = 0.9
+//! temperature = 0.7
+//! ```
+//!
+//! Like the command-line flags, top-level `temperature`, `top_p` and `top_k`
+//! keys adjust the `[sampling]` strategy, see `Config::override_sampling`.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ModelArgs;
+use crate::generation_config::GenerationConfig;
+use crate::sampling::SamplingConfig;
+
+/// Config file read when none is given explicitly, if it exists
+pub const DEFAULT_CONFIG_FILE: &str = "llm-rs.toml";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Path to the GGUF model or to a directory with config.json and safetensors,
+    /// or a repo id in the HuggingFace cache such as `HuggingFaceTB/SmolLM3-3B`
+    pub model: String,
+    /// Branch, tag or commit of the cached model repo; defaults to `main`
+    pub revision: Option<String>,
+    /// Path to tokenizer.json, or a cached repo that has one at its `main` revision;
+    /// defaults to the one next to the model, or the vocabulary embedded in a GGUF file
+    /// when there is none
+    pub tokenizer: Option<String>,
+    /// `auto`, `cpu`, `cuda[:N]` or `metal[:N]`, see `select_device`
+    pub device: String,
+    /// `f32`, `bf16` or `f16` for safetensors models, see `select_dtype`
+    pub dtype: Option<String>,
+    /// Name of the chat template to use when the model ships several
+    pub chat_template: Option<String>,
+    /// Address the HTTP server listens on
+    pub address: String,
+    #[serde(flatten)]
+    pub generation: GenerationConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            model: "model/llm.gguf".to_string(),
+            revision: None,
+            tokenizer: None,
+            device: "auto".to_string(),
+            dtype: None,
+            chat_template: None,
+            address: "127.0.0.1:8080".to_string(),
+            generation: GenerationConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Read `path`, or `$LLM_RS_CONFIG`, or `llm-rs.toml` if present, then apply
+    /// environment overrides
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => std::env::var_os("LLM_RS_CONFIG")
+                .map(PathBuf::from)
+                .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|p| p.exists())),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Parse a config file, as JSON if it ends in `.json` and as TOML otherwise
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config {}", path.display()))?;
+        let file: ConfigFile = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&text)?
+        } else {
+            toml::from_str(&text)?
+        };
+        let mut config = file.config;
+        config.override_sampling(file.temperature, file.top_p, file.top_k);
+        Ok(config)
+    }
+
+    /// Override settings from `LLM_RS_*` environment variables
+    pub fn apply_env(&mut self) -> Result<()> {
+        if let Some(model) = env("LLM_RS_MODEL")? {
+            self.model = model;
+        }
+        if let Some(revision) = env("LLM_RS_REVISION")? {
+            self.revision = Some(revision);
+        }
+        if let Some(tokenizer) = env("LLM_RS_TOKENIZER")? {
+            self.tokenizer = Some(tokenizer);
+        }
+        if let Some(device) = env("LLM_RS_DEVICE")? {
+            self.device = device;
+        }
+        if let Some(dtype) = env("LLM_RS_DTYPE")? {
+            self.dtype = Some(dtype);
+        }
+        if let Some(system_prompt) = env("LLM_RS_SYSTEM_PROMPT")? {
+            self.generation.system_prompt = system_prompt;
+        }
+        if let Some(think) = env("LLM_RS_THINK")? {
+            self.generation.enable_thinking = think;
+        }
+        if let Some(chat_template) = env("LLM_RS_CHAT_TEMPLATE")? {
+            self.chat_template = Some(chat_template);
+        }
+        if let Some(max_tokens) = env("LLM_RS_MAX_TOKENS")? {
+            self.generation.max_tokens = max_tokens;
+        }
+        if let Some(seed) = env("LLM_RS_SEED")? {
+            self.generation.seed = Some(seed);
+        }
+        if let Some(address) = env("LLM_RS_ADDRESS")? {
+            self.address = address;
+        }
+        self.override_sampling(
+            env("LLM_RS_TEMPERATURE")?,
+            env("LLM_RS_TOP_P")?,
+            env("LLM_RS_TOP_K")?,
+        );
+        Ok(())
+    }
+
+    /// Adjust the sampling strategy: `top_p` and `top_k` pick the strategy,
+    /// `temperature` keeps the strategy and only changes the temperature
+    pub fn override_sampling(
+        &mut self,
+        temperature: Option<f64>,
+        top_p: Option<f64>,
+        top_k: Option<usize>,
+    ) {
+        let current = &self.generation.sampling;
+        let mut sampling = match (top_k, top_p) {
+            (None, None) if temperature.is_none() => return,
+            (None, None) => current.clone(),
+            (Some(k), Some(p)) => SamplingConfig::TopKTopP {
+                k,
+                p,
+                temperature: current.temperature(),
+            },
+            (Some(k), None) => SamplingConfig::TopK {
+                k,
+                temperature: current.temperature(),
+            },
+            (None, Some(p)) => SamplingConfig::TopP {
+                p,
+                temperature: current.temperature(),
+            },
+        };
+        if let Some(temperature) = temperature {
+            sampling = sampling.with_temperature(temperature);
+        }
+        self.generation.sampling = sampling;
+    }
+
+    pub fn model_args(&self, interrupt_signal: Arc<AtomicBool>) -> ModelArgs {
+        ModelArgs {
+            model_path: self.model.clone(),
+            revision: self.revision.clone(),
+            device: self.device.clone(),
+            dtype: self.dtype.clone(),
+            tokenizer_path: self.tokenizer.clone(),
+            chat_template: self.chat_template.clone(),
+            generation: self.generation.clone(),
+            interrupt_signal,
+        }
+    }
+}
+
+/// Contents of a config file: the settings plus the sampling shorthands
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    config: Config,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    top_k: Option<usize>,
+}
+
+/// Read and parse an environment variable; unset or empty means `None`
+fn env<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|err| anyhow::anyhow!("invalid {name}={value:?}: {err}")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_toml(name: &str, text: &str) -> Config {
+        let path = std::env::temp_dir().join(format!("llm-rs-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let config = Config::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        config.unwrap()
+    }
+
+    #[test]
+    fn top_level_sampling_keys() {
+        let config = from_toml("flat", "max_tokens = 8\ntemperature = 0.2\ntop_p = 0.9\n");
+        assert_eq!(config.generation.max_tokens, 8);
+        assert_eq!(
+            config.generation.sampling,
+            SamplingConfig::TopP {
+                p: 0.9,
+                temperature: 0.2
+            }
+        );
+    }
+
+    #[test]
+    fn top_level_temperature_keeps_the_strategy() {
+        let config = from_toml(
+            "temperature",
+            "temperature = 1\n[sampling]\nstrategy = \"top_k\"\nk = 20\ntemperature = 0.5\n",
+        );
+        assert_eq!(
+            config.generation.sampling,
+            SamplingConfig::TopK {
+                k: 20,
+                temperature: 1.0
+            }
+        );
+    }
+}
@@ -1,176 +1,910 @@
-use crate::chat_template::{ChatTemplate, ChatTemplateOptions, Message};
+use crate::chat_template::{ChatTemplate, ChatTemplateOptions, Conversation, Message};
+use crate::generation_config::{GenerationConfig, GenerationConfigError, GenerationRequest};
+use crate::model::{Architecture, CausalLM};
+use crate::sampling::{Sampler, SamplingConfig, random_seed};
+use crate::sink::{NullSink, TokenSink};
 use crate::tokenizer::TokenOutputStream;
 use anyhow::{Error as E, Result};
-use candle_transformers::generation::{LogitsProcessor, Sampling};
-use candle_transformers::models::mimi::candle::{DType, Device, Tensor};
-use candle_transformers::models::smol::quantized_smollm3::QuantizedModelForCausalLM;
-use std::io::Write;
+use candle_transformers::models::mimi::candle::{D, DType, Device, Tensor};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use tokenizers::Tokenizer;
 
+/// Why a generation run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model produced its end-of-sequence token
+    Eos,
+    /// One of the `stop_sequences` was generated
+    Stop,
+    /// The `max_tokens` limit, the answer budget or the context length was reached
+    Length,
+    /// The `interrupt_signal` was raised
+    Interrupted,
+}
+
+/// The prompt leaves no room in the model's context for the answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptTooLong {
+    pub prompt_tokens: usize,
+    pub context_length: usize,
+}
+
+impl std::fmt::Display for PromptTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "prompt is {} tokens long, the model's context holds {}",
+            self.prompt_tokens, self.context_length
+        )
+    }
+}
+
+impl std::error::Error for PromptTooLong {}
+
+/// Result of a single `run_generation` call
+#[derive(Debug, Clone)]
+pub struct GenerationOutput {
+    /// Final answer, without the reasoning block
+    pub text: String,
+    /// Content of the `<think>` block when thinking mode was used
+    pub reasoning: Option<String>,
+    pub tokens: Vec<u32>,
+    pub finish_reason: FinishReason,
+    /// Sampling seed of this run
+    pub seed: u64,
+    pub prompt_tokens: usize,
+    /// Prompt tokens that were served from the KV cache of a previous run
+    pub cached_prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Part of `completion_tokens` spent inside the `<think>` block
+    pub reasoning_tokens: usize,
+    /// Time spent on the prompt forward pass (time to first token)
+    pub prompt_duration: Duration,
+    pub generation_duration: Duration,
+}
+
+/// One step of a generation run, yielded by `GenerationStream`
+#[derive(Debug, Clone)]
+pub struct GenerationEvent {
+    /// Sampled token id, `None` for the final event of an interrupted run
+    pub token: Option<u32>,
+    /// Newly decoded text; empty while a multi-token character is still incomplete
+    pub text: String,
+    /// Log-probability of `token` after the repeat penalty
+    pub logprob: Option<f32>,
+    /// Whether `text` belongs to the `<think>` reasoning block rather than the answer
+    pub reasoning: bool,
+    /// Set on the last event of the run
+    pub finish_reason: Option<FinishReason>,
+}
+
+impl GenerationOutput {
+    pub fn tokens_per_second(&self) -> f64 {
+        let secs = self.generation_duration.as_secs_f64();
+        if secs > 0.0 {
+            self.completion_tokens as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
 pub struct TextGeneration {
-    pub model: QuantizedModelForCausalLM,
+    pub model: Box<dyn CausalLM>,
     pub device: Device,
     pub tokenizer: TokenOutputStream,
-    pub repeat_penalty: f32,
-    pub repeat_last_n: usize,
-    pub enable_thinking: bool,
-    pub top_p: f64,
-    pub temperature: f64,
-    pub sample_len: usize,
-    pub system_prompt: String,
+    /// Defaults for every run; a `GenerationRequest` overrides them for a single call
+    config: GenerationConfig,
+    /// Token ids that end the generation, e.g. both `<|eot_id|>` and `<|end_of_text|>` for Llama 3
+    pub eos_token_ids: Vec<u32>,
+    /// Template shipped with the model; `None` uses the built-in ChatML, with SmolLM3's
+    /// metadata block for SmolLM3 models
+    pub chat_template: Option<ChatTemplate>,
     pub interrupt_signal: Arc<AtomicBool>,
+    /// Tokens whose keys and values are currently stored in the model's KV cache
+    cached_tokens: Vec<u32>,
 }
 
 impl TextGeneration {
     pub fn clear_cache(&mut self) {
         self.model.clear_kv_cache();
+        self.cached_tokens.clear();
+    }
+    /// Settings used by runs that do not override them
+    pub fn config(&self) -> &GenerationConfig {
+        &self.config
+    }
+    /// Replace the default settings, e.g. from an interactive command
+    pub fn set_config(&mut self, config: GenerationConfig) -> Result<(), GenerationConfigError> {
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+    pub fn run_generation(
+        &mut self,
+        prompt_str: &str,
+        request: &GenerationRequest,
+    ) -> Result<GenerationOutput> {
+        self.run_generation_with_sink(prompt_str, request, &mut NullSink)
+    }
+    /// Same as `run_generation`, forwarding each decoded chunk to `sink` as it is produced
+    pub fn run_generation_with_sink(
+        &mut self,
+        prompt_str: &str,
+        request: &GenerationRequest,
+        sink: &mut dyn TokenSink,
+    ) -> Result<GenerationOutput> {
+        self.stream(prompt_str, request)?.into_output(sink)
     }
-    pub fn run_generation(&mut self, prompt_str: &str) -> Result<()> {
+    /// Start a pull-based generation; each `next()` runs one forward pass and yields one token.
+    /// Dropping the stream stops the generation.
+    pub fn stream(
+        &mut self,
+        prompt_str: &str,
+        request: &GenerationRequest,
+    ) -> Result<GenerationStream<'_>> {
+        let config = self.config.with_request(request)?;
+        let formatted_prompt = self
+            .conversation(&config, &config.system_prompt)
+            .user_turn(prompt_str)?;
+        self.start(&formatted_prompt, config)
+    }
+    /// Like `stream`, but `prompt` is fed to the model as is, without applying the chat template
+    pub fn stream_raw(
+        &mut self,
+        prompt: &str,
+        request: &GenerationRequest,
+    ) -> Result<GenerationStream<'_>> {
+        let config = self.config.with_request(request)?;
+        self.start(prompt, config)
+    }
+    fn start(&mut self, prompt: &str, config: GenerationConfig) -> Result<GenerationStream<'_>> {
         self.interrupt_signal.store(false, Ordering::Relaxed);
-        let formatted_prompt = self.format_prompt(&prompt_str);
+        self.tokenizer.clear();
+        self.tokenizer
+            .set_stop_sequences(config.stop_sequences.clone());
         let tokens = self
             .tokenizer
             .tokenizer()
-            .encode(formatted_prompt.as_str(), false)
+            .encode(prompt, false)
             .map_err(E::msg)?;
-        let tokens = tokens.get_ids();
+        let prompt_tokens = tokens.get_ids().to_vec();
+        let context_length = self.model.context_length();
+        if prompt_tokens.len() >= context_length {
+            return Err(PromptTooLong {
+                prompt_tokens: prompt_tokens.len(),
+                context_length,
+            }
+            .into());
+        }
+
+        // Reuse the KV cache when it holds a prefix of the new prompt (e.g. the previous
+        // turns of a conversation). The cache cannot be truncated, so any divergence resets it.
+        let common_len = self
+            .cached_tokens
+            .iter()
+            .zip(&prompt_tokens)
+            .take_while(|(cached, new)| cached == new)
+            .count();
+        if common_len < self.cached_tokens.len()
+            || common_len >= prompt_tokens.len()
+            || !self.model.supports_offset_prefill()
+        {
+            self.clear_cache();
+        }
 
-        let sampling = Sampling::TopP {
-            p: self.top_p,
-            temperature: self.temperature,
+        let seed = config.seed.unwrap_or_else(random_seed);
+        let think_tokens = self
+            .tokenizer
+            .get_token("<think>")
+            .zip(self.tokenizer.get_token("</think>"));
+        // The template may have already opened the reasoning block (e.g. `chatml_with_thinking`)
+        let reasoning = think_tokens.is_some_and(|(start, end)| {
+            let last_start = prompt_tokens.iter().rposition(|&t| t == start);
+            let last_end = prompt_tokens.iter().rposition(|&t| t == end);
+            last_start > last_end
+        });
+
+        Ok(GenerationStream {
+            think_tokens,
+            reasoning,
+            reasoning_tokens: 0,
+            answer_tokens: 0,
+            seed,
+            sampler: Sampler::new(seed, config.sampling.clone()),
+            config,
+            cached_prompt_tokens: self.cached_tokens.len(),
+            prompt_tokens,
+            tokens: Vec::new(),
+            finished: false,
+            prompt_duration: Duration::ZERO,
+            generation_start: None,
+            generation: self,
+        })
+    }
+    /// Add terminators reported by the model files, ignoring ones already known
+    pub fn add_eos_token_ids(&mut self, token_ids: impl IntoIterator<Item = u32>) {
+        for token_id in token_ids {
+            if !self.eos_token_ids.contains(&token_id) {
+                self.eos_token_ids.push(token_id);
+            }
+        }
+    }
+    /// Run `tokens` through the model right after the cached ones and record them as cached
+    fn forward_cached(&mut self, tokens: &[u32]) -> Result<Tensor> {
+        let index_pos = self.cached_tokens.len();
+        let logits = Tensor::new(tokens, &self.device)
+            .and_then(|input| input.unsqueeze(0))
+            .and_then(|input| self.model.forward(&input, index_pos));
+        match logits {
+            Ok(logits) => {
+                self.cached_tokens.extend_from_slice(tokens);
+                Ok(logits)
+            }
+            Err(err) => {
+                // Some layers may already have appended to their cache
+                self.clear_cache();
+                Err(err.into())
+            }
+        }
+    }
+    pub fn new_conversation(&self) -> Conversation {
+        self.new_conversation_with_system(&self.config.system_prompt)
+    }
+    /// Start a conversation with a system prompt other than the configured one
+    pub fn new_conversation_with_system(&self, system_prompt: &str) -> Conversation {
+        self.conversation(&self.config, system_prompt)
+    }
+    fn conversation(&self, config: &GenerationConfig, system_prompt: &str) -> Conversation {
+        let options = if config.enable_thinking {
+            ChatTemplateOptions::for_generation().with_thinking()
+        } else {
+            ChatTemplateOptions::for_generation()
         };
 
-        let mut logits_processor = LogitsProcessor::from_sampling(299792458, sampling);
+        match &self.chat_template {
+            // Some templates reject a system role, so an empty prompt sends none
+            Some(template) if system_prompt.is_empty() => {
+                Conversation::without_system(template.clone()).with_options(options)
+            }
+            // The model's own template renders its system block itself
+            Some(template) => {
+                Conversation::new(template.clone(), system_prompt).with_options(options)
+            }
+            None => {
+                let template = if config.enable_thinking {
+                    ChatTemplate::chatml_with_thinking()
+                } else {
+                    ChatTemplate::chatml()
+                };
+                let system_prompt = if self.model.architecture() == Some(Architecture::SmolLm3) {
+                    system_message(config, system_prompt)
+                } else {
+                    system_prompt.to_string()
+                };
+                Conversation::new(template, system_prompt).with_options(options)
+            }
+        }
+    }
+    /// Run one chat turn: add `content` as a user message, generate the reply and record it.
+    /// The conversation was formatted when it was created, so only sampling and length
+    /// settings of `request` apply. On error the conversation is left unchanged.
+    pub fn chat(
+        &mut self,
+        conversation: &mut Conversation,
+        content: &str,
+        request: &GenerationRequest,
+        sink: &mut dyn TokenSink,
+    ) -> Result<GenerationOutput> {
+        let prompt = conversation.user_turn(content)?;
+        let output = self
+            .stream_raw(&prompt, request)
+            .and_then(|stream| stream.into_output(sink));
+        match output {
+            Ok(output) => {
+                conversation.assistant_response(output.text.as_str());
+                Ok(output)
+            }
+            Err(err) => {
+                conversation.pop_message();
+                Err(err)
+            }
+        }
+    }
+    /// Render a complete chat history, e.g. from an API request, ready for generation.
+    /// A leading system message replaces the configured system prompt.
+    pub fn format_messages(
+        &self,
+        messages: &[Message],
+        request: &GenerationRequest,
+    ) -> Result<String> {
+        let config = self.config.with_request(request)?;
+        let (system_prompt, messages) = match messages.split_first() {
+            Some((first, rest)) if first.role == "system" => (first.content.as_str(), rest),
+            _ => (config.system_prompt.as_str(), messages),
+        };
+        let mut conversation = self.conversation(&config, system_prompt);
+        for message in messages {
+            conversation.add_message(message.clone());
+        }
+        Ok(conversation.prompt()?)
+    }
+    /// Start building a generation over `model`; settings default to `GenerationConfig::default()`
+    pub fn builder(
+        model: impl CausalLM + 'static,
+        tokenizer: Tokenizer,
+        device: &Device,
+    ) -> TextGenerationBuilder {
+        TextGenerationBuilder {
+            model: Box::new(model),
+            tokenizer,
+            device: device.clone(),
+            config: GenerationConfig::default(),
+            chat_template: None,
+            interrupt_signal: None,
+        }
+    }
+}
 
-        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
-        let logits = self.model.forward(&input, 0)?;
-        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-        let mut next_token = logits_processor.sample(&logits)?;
+/// System block of the built-in ChatML template, with SmolLM3's metadata
+fn system_message(config: &GenerationConfig, system_prompt: &str) -> String {
+    // Build system message with SmolLM3's metadata format
+    let now = chrono::Local::now();
+    let today_date = now.format("%d %B %Y").to_string();
 
-        let eos_token = self.get_eos_token();
+    let reasoning_mode = if config.enable_thinking {
+        "/think"
+    } else {
+        "/no_think"
+    };
+
+    format!(
+        "## Metadata\n\n\
+             Knowledge Cutoff Date: February 2026\n\
+             Today Date: {}\n\
+             Reasoning Mode: {}\n\n\
+             ## Custom Instructions\n\n\
+             {}",
+        today_date, reasoning_mode, system_prompt
+    )
+}
+
+/// Configures and validates a `TextGeneration`, see `TextGeneration::builder`
+pub struct TextGenerationBuilder {
+    model: Box<dyn CausalLM>,
+    tokenizer: Tokenizer,
+    device: Device,
+    config: GenerationConfig,
+    chat_template: Option<ChatTemplate>,
+    interrupt_signal: Option<Arc<AtomicBool>>,
+}
+
+impl TextGenerationBuilder {
+    /// Replace all settings at once, e.g. with a config read from a file
+    pub fn config(mut self, config: GenerationConfig) -> Self {
+        self.config = config;
+        self
+    }
+    pub fn sampling(mut self, sampling: SamplingConfig) -> Self {
+        self.config.sampling = sampling;
+        self
+    }
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.config.seed = seed;
+        self
+    }
+    pub fn repeat_penalty(mut self, repeat_penalty: f32) -> Self {
+        self.config.repeat_penalty = repeat_penalty;
+        self
+    }
+    pub fn repeat_last_n(mut self, repeat_last_n: usize) -> Self {
+        self.config.repeat_last_n = repeat_last_n;
+        self
+    }
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.config.max_tokens = max_tokens;
+        self
+    }
+    pub fn thinking_budget(mut self, thinking_budget: Option<usize>) -> Self {
+        self.config.thinking_budget = thinking_budget;
+        self
+    }
+    pub fn answer_budget(mut self, answer_budget: Option<usize>) -> Self {
+        self.config.answer_budget = answer_budget;
+        self
+    }
+    pub fn stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.config.stop_sequences = stop_sequences;
+        self
+    }
+    pub fn system_prompt(mut self, system_prompt: impl Into<String>) -> Self {
+        self.config.system_prompt = system_prompt.into();
+        self
+    }
+    pub fn enable_thinking(mut self, enable_thinking: bool) -> Self {
+        self.config.enable_thinking = enable_thinking;
+        self
+    }
+    pub fn chat_template(mut self, chat_template: Option<ChatTemplate>) -> Self {
+        self.chat_template = chat_template;
+        self
+    }
+    /// Flag that stops the current run when raised; a fresh one is created if not set
+    pub fn interrupt_signal(mut self, interrupt_signal: Arc<AtomicBool>) -> Self {
+        self.interrupt_signal = Some(interrupt_signal);
+        self
+    }
+    /// Validate the settings and create the `TextGeneration`
+    pub fn build(self) -> Result<TextGeneration, GenerationConfigError> {
+        self.config.validate()?;
+        Ok(TextGeneration {
+            device: self.device,
+            model: self.model,
+            eos_token_ids: chatml_eos_token_ids(&self.tokenizer),
+            tokenizer: TokenOutputStream::new(self.tokenizer),
+            config: self.config,
+            chat_template: self.chat_template,
+            interrupt_signal: self.interrupt_signal.unwrap_or_default(),
+            cached_tokens: Vec::new(),
+        })
+    }
+}
 
-        let mut all_tokens = vec![next_token];
-        println!("\nОтвет:");
-        if let Some(t) = self.tokenizer.next_token(next_token)? {
-            print!("{t}");
-            std::io::stdout().flush()?;
+/// Resumable prefill/decode loop created by `TextGeneration::stream`
+pub struct GenerationStream<'a> {
+    generation: &'a mut TextGeneration,
+    /// Settings of this run, the instance defaults with the request applied
+    config: GenerationConfig,
+    seed: u64,
+    sampler: Sampler,
+    prompt_tokens: Vec<u32>,
+    /// Length of the prompt prefix that was already in the KV cache
+    cached_prompt_tokens: usize,
+    tokens: Vec<u32>,
+    /// Ids of `<think>` and `</think>`, if the tokenizer has them as single tokens
+    think_tokens: Option<(u32, u32)>,
+    /// Whether the model is currently inside a `<think>` block
+    reasoning: bool,
+    reasoning_tokens: usize,
+    answer_tokens: usize,
+    finished: bool,
+    prompt_duration: Duration,
+    generation_start: Option<Instant>,
+}
+
+impl GenerationStream<'_> {
+    pub fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens.len()
+    }
+
+    /// Seed used by the sampler, pass it back through `GenerationRequest::seed` to reproduce the run
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Tokens generated inside the `<think>` block so far
+    pub fn reasoning_tokens(&self) -> usize {
+        self.reasoning_tokens
+    }
+
+    /// Number of prompt tokens reused from the KV cache instead of being prefilled
+    pub fn cached_prompt_tokens(&self) -> usize {
+        self.cached_prompt_tokens
+    }
+
+    /// Tokens generated so far
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn prompt_duration(&self) -> Duration {
+        self.prompt_duration
+    }
+
+    pub fn generation_duration(&self) -> Duration {
+        self.generation_start
+            .map(|start| start.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Drive the stream to completion, forwarding text to `sink`, and collect the result
+    pub fn into_output(mut self, sink: &mut dyn TokenSink) -> Result<GenerationOutput> {
+        let mut text = String::new();
+        let mut reasoning: Option<String> = None;
+        let mut finish_reason = FinishReason::Length;
+        for event in self.by_ref() {
+            let event = event?;
+            if event.reasoning {
+                let reasoning = reasoning.get_or_insert_default();
+                if !event.text.is_empty() {
+                    sink.on_reasoning(&event.text)?;
+                    reasoning.push_str(&event.text);
+                }
+            } else if !event.text.is_empty() {
+                sink.on_token(&event.text)?;
+                text.push_str(&event.text);
+            }
+            if let Some(reason) = event.finish_reason {
+                finish_reason = reason;
+            }
+        }
+        if let Some(reasoning) = &mut reasoning {
+            *reasoning = reasoning.trim().to_string();
+            text = text.trim_start().to_string();
         }
 
-        let to_sample = self.sample_len.saturating_sub(1);
+        Ok(GenerationOutput {
+            text,
+            reasoning,
+            tokens: self.tokens.clone(),
+            finish_reason,
+            seed: self.seed,
+            prompt_tokens: self.prompt_tokens(),
+            cached_prompt_tokens: self.cached_prompt_tokens,
+            completion_tokens: self.tokens.len(),
+            reasoning_tokens: self.reasoning_tokens,
+            prompt_duration: self.prompt_duration(),
+            generation_duration: self.generation_duration(),
+        })
+    }
 
-        for index in 0..to_sample {
-            if self.interrupt_signal.load(Ordering::Relaxed) {
-                println!("\n[Генерация прервана]");
-                self.clear_cache();
-                break;
+    fn step(&mut self) -> Result<GenerationEvent> {
+        let generation = &mut *self.generation;
+        let start_prompt = Instant::now();
+        let logits = match self.tokens.last() {
+            None => generation.forward_cached(&self.prompt_tokens[self.cached_prompt_tokens..])?,
+            Some(&last_token) => {
+                if generation.interrupt_signal.load(Ordering::Relaxed) {
+                    let text = generation
+                        .tokenizer
+                        .decode_rest()
+                        .map_err(E::msg)?
+                        .unwrap_or_default();
+                    return Ok(GenerationEvent {
+                        token: None,
+                        text,
+                        logprob: None,
+                        reasoning: self.reasoning,
+                        finish_reason: Some(FinishReason::Interrupted),
+                    });
+                }
+                generation.forward_cached(&[last_token])?
             }
-            let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
-            let logits = self.model.forward(&input, tokens.len() + index)?;
-            let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-
-            let start_at = all_tokens.len().saturating_sub(self.repeat_last_n);
-            let logits = candle_transformers::utils::apply_repeat_penalty(
-                &logits,
-                self.repeat_penalty,
-                &all_tokens[start_at..],
-            )?;
-
-            next_token = logits_processor.sample(&logits)?;
-            all_tokens.push(next_token);
-
-            if let Some(t) = self.tokenizer.next_token(next_token)? {
-                print!("{t}");
-                std::io::stdout().flush()?;
+        };
+        let logits = logits.squeeze(0)?.to_dtype(DType::F32)?;
+
+        let start_at = self.tokens.len().saturating_sub(self.config.repeat_last_n);
+        let logits = candle_transformers::utils::apply_repeat_penalty(
+            &logits,
+            self.config.repeat_penalty,
+            &self.tokens[start_at..],
+        )?;
+
+        let next_token = match self.think_tokens {
+            // Reasoning budget exhausted: close the block instead of sampling
+            Some((_, end))
+                if self.reasoning
+                    && self
+                        .config
+                        .thinking_budget
+                        .is_some_and(|budget| self.reasoning_tokens >= budget) =>
+            {
+                end
             }
+            _ => self.sampler.sample(&logits)?,
+        };
+        let logprob = candle_nn::ops::log_softmax(&logits, D::Minus1)?
+            .get(next_token as usize)?
+            .to_scalar::<f32>()?;
+        if self.generation_start.is_none() {
+            self.prompt_duration = start_prompt.elapsed();
+            self.generation_start = Some(Instant::now());
+        }
+        self.tokens.push(next_token);
 
-            if next_token == eos_token {
-                break;
+        let reasoning = self.reasoning;
+        let mut text = match self.think_tokens {
+            // Think markers are not part of either text: flush what was pending before them
+            // and start decoding the next block from scratch
+            Some((start, end)) if next_token == start || next_token == end => {
+                let rest = generation.tokenizer.decode_rest().map_err(E::msg)?;
+                generation.tokenizer.clear();
+                self.reasoning = next_token == start;
+                rest.unwrap_or_default()
             }
+            _ => {
+                if reasoning {
+                    self.reasoning_tokens += 1;
+                } else {
+                    self.answer_tokens += 1;
+                }
+                generation
+                    .tokenizer
+                    .next_token(next_token)?
+                    .unwrap_or_default()
+            }
+        };
+
+        let finish_reason = if generation.eos_token_ids.contains(&next_token) {
+            Some(FinishReason::Eos)
+        } else if generation.tokenizer.stopped() {
+            Some(FinishReason::Stop)
+        } else if self.tokens.len() >= self.config.max_tokens
+            || self
+                .config
+                .answer_budget
+                .is_some_and(|budget| self.answer_tokens >= budget)
+            // The next forward pass would not fit in the context
+            || generation.cached_tokens.len() >= generation.model.context_length()
+        {
+            Some(FinishReason::Length)
+        } else {
+            None
+        };
+        if finish_reason.is_some()
+            && let Some(rest) = generation.tokenizer.decode_rest().map_err(E::msg)?
+        {
+            text.push_str(&rest);
         }
 
-        if let Some(rest) = self.tokenizer.decode_rest().map_err(E::msg)? {
-            print!("{rest}");
+        Ok(GenerationEvent {
+            token: Some(next_token),
+            text,
+            logprob: Some(logprob),
+            reasoning,
+            finish_reason,
+        })
+    }
+}
+
+impl Iterator for GenerationStream<'_> {
+    type Item = Result<GenerationEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
         }
-        println!();
-        Ok(())
+        let event = self.step();
+        match &event {
+            Ok(event) if event.finish_reason.is_none() => {}
+            _ => self.finished = true,
+        }
+        Some(event)
     }
-    fn get_eos_token(&self) -> u32 {
-        let vocab = self.tokenizer.tokenizer().get_vocab(true);
-        if let Some(&eos_id) = vocab.get("<|im_end|>") {
-            return eos_id;
-        }
-        if let Some(&eos_id) = vocab.get("<|endoftext|>") {
-            return eos_id;
-        }
-
-        128012 // Default SmolLM3 EOS token
-    }
-    fn format_prompt(&self, prompt: &str) -> String {
-        // let template = if self.enable_thinking {
-        //     ChatTemplate::chatml_with_thinking()
-        // } else {
-        //     ChatTemplate::chatml()
-        // };
-        let template = ChatTemplate::chatml();
-        // Build system message with SmolLM3's metadata format
-        let now = chrono::Local::now();
-        let today_date = now.format("%d %B %Y").to_string();
-
-        let reasoning_mode = "/no_think";
-        // let reasoning_mode = if self.enable_thinking {
-        //     "/think"
-        // } else {
-        //     "/no_think"
-        // };
-
-        let system_content = format!(
-            "## Metadata\n\n\
-             Knowledge Cutoff Date: February 2026\n\
-             Today Date: {}\n\
-             Reasoning Mode: {}\n\n\
-             ## Custom Instructions\n\n\
-             {}",
-            today_date, reasoning_mode, self.system_prompt
-        );
+}
+
+/// Terminators of the built-in ChatML template, used until the model files provide their own
+fn chatml_eos_token_ids(tokenizer: &Tokenizer) -> Vec<u32> {
+    let vocab = tokenizer.get_vocab(true);
+    ["<|im_end|>", "<|endoftext|>"]
+        .iter()
+        .filter_map(|token| vocab.get(*token).copied())
+        .collect()
+}
 
-        let messages = vec![Message::system(system_content), Message::user(prompt)];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scripted_generation, scripted_generation_with};
+    use anyhow::bail;
 
-        let options = ChatTemplateOptions::for_generation();
-        // let options = if self.enable_thinking {
-        //     ChatTemplateOptions::for_generation().with_thinking()
-        // } else {
-        //     ChatTemplateOptions::for_generation()
-        // };
+    const PIECES: &[&str] = &[
+        "a",
+        "b",
+        "c",
+        "d",
+        "q",
+        "hi",
+        " there",
+        "ok",
+        " ",
+        "```",
+        "\n",
+        "hmm",
+        " answer",
+        "<think>",
+        "</think>",
+        "<|im_end|>",
+        "<|eot|>",
+    ];
 
-        template.apply(&messages, &options).unwrap()
+    fn request() -> GenerationRequest {
+        GenerationRequest::default()
     }
-    pub fn new(
-        device: &Device,
-        model: QuantizedModelForCausalLM,
-        tokenizer: Tokenizer,
-        temp: Option<f64>,
-        top_p: Option<f64>,
-        repeat_penalty: Option<f32>,
-        repeat_last_n: Option<usize>,
-        sample_len: Option<usize>,
-        system_prompt: Option<String>,
-        enable_thinking: Option<bool>,
-        interrupt_signal: Arc<AtomicBool>,
-    ) -> Self {
-        Self {
-            device: device.clone(),
-            model,
-            tokenizer: TokenOutputStream::new(tokenizer),
-            repeat_penalty: repeat_penalty.unwrap_or(1.1),
-            repeat_last_n: repeat_last_n.unwrap_or(64),
-            sample_len: sample_len.unwrap_or(1000),
-            temperature: temp.unwrap_or(0.6),
-            top_p: top_p.unwrap_or(0.5),
-            system_prompt: system_prompt.unwrap_or("You are a helpful assistant".to_string()),
-            enable_thinking: enable_thinking.unwrap_or(false),
-            interrupt_signal: interrupt_signal,
+
+    #[test]
+    fn stops_at_eos() {
+        let (mut generation, _) = scripted_generation(PIECES, &["hi", " there", "<|im_end|>", "a"]);
+        let output = run(&mut generation, "a b", &request());
+        assert_eq!(output.finish_reason, FinishReason::Eos);
+        assert_eq!(output.text, "hi there");
+        assert_eq!(output.completion_tokens, 3);
+    }
+
+    #[test]
+    fn stops_at_any_added_eos() {
+        let (mut generation, _) = scripted_generation(PIECES, &["hi", "<|eot|>", "a"]);
+        let eot = generation.tokenizer.get_token("<|eot|>").unwrap();
+        generation.add_eos_token_ids([eot]);
+        let output = run(&mut generation, "a", &request());
+        assert_eq!(output.finish_reason, FinishReason::Eos);
+        assert_eq!(output.text, "hi");
+    }
+
+    #[test]
+    fn stops_at_max_tokens() {
+        let (mut generation, _) = scripted_generation(PIECES, &["a"]);
+        let request = GenerationRequest {
+            max_tokens: Some(3),
+            ..request()
+        };
+        let output = run(&mut generation, "a", &request);
+        assert_eq!(output.finish_reason, FinishReason::Length);
+        assert_eq!(output.text, "aaa");
+    }
+
+    #[test]
+    fn stops_at_stop_sequence() {
+        let (mut generation, _) =
+            scripted_generation(PIECES, &["ok", " ", "```", "\n", "\n", "```"]);
+        let request = GenerationRequest {
+            stop_sequences: Some(vec!["```".to_string()]),
+            max_tokens: Some(8),
+            ..request()
+        };
+        let output = run(&mut generation, "a", &request);
+        assert_eq!(output.finish_reason, FinishReason::Stop);
+        assert_eq!(output.text, "ok ");
+        assert_eq!(output.completion_tokens, 3);
+    }
+
+    #[test]
+    fn thinking_budget_forces_end_of_block() {
+        let (mut generation, _) =
+            scripted_generation(PIECES, &["hmm", "hmm", "hmm", " answer", "<|im_end|>"]);
+        let request = GenerationRequest {
+            thinking_budget: Some(2),
+            ..request()
+        };
+        // The prompt leaves the reasoning block open
+        let output = run(&mut generation, "q <think>", &request);
+        let end = generation.tokenizer.get_token("</think>").unwrap();
+        assert_eq!(output.tokens[2], end);
+        assert_eq!(output.reasoning.as_deref(), Some("hmmhmm"));
+        assert_eq!(output.reasoning_tokens, 2);
+        assert_eq!(output.text, "answer");
+        assert_eq!(output.finish_reason, FinishReason::Eos);
+    }
+
+    #[test]
+    fn reuses_cached_prompt_prefix() {
+        let (mut generation, calls) = scripted_generation(PIECES, &["a"]);
+        let request = GenerationRequest {
+            max_tokens: Some(1),
+            ..request()
+        };
+        run(&mut generation, "a b", &request);
+        let output = run(&mut generation, "a b c d", &request);
+        assert_eq!(output.cached_prompt_tokens, 2);
+        assert_eq!(*calls.lock().unwrap(), [(0, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn prefills_from_start_without_offset_prefill() {
+        let (mut generation, calls) =
+            scripted_generation_with(PIECES, &["a"], |model| model.without_offset_prefill());
+        let request = GenerationRequest {
+            max_tokens: Some(1),
+            ..request()
+        };
+        run(&mut generation, "a b", &request);
+        let output = run(&mut generation, "a b c d", &request);
+        assert_eq!(output.cached_prompt_tokens, 0);
+        assert_eq!(*calls.lock().unwrap(), [(0, 2), (0, 4)]);
+    }
+
+    #[test]
+    fn interrupt_stops_the_run() {
+        let (mut generation, _) = scripted_generation(PIECES, &["a", "b", "c", "d"]);
+        let signal = generation.interrupt_signal.clone();
+        let mut sink = |_: &str| {
+            signal.store(true, Ordering::Relaxed);
+            Ok(())
+        };
+        let output = generation
+            .stream_raw("a", &request())
+            .unwrap()
+            .into_output(&mut sink)
+            .unwrap();
+        assert_eq!(output.finish_reason, FinishReason::Interrupted);
+        assert_eq!(output.text, "a");
+        assert_eq!(output.completion_tokens, 1);
+    }
+
+    #[test]
+    fn failed_chat_turn_leaves_conversation_unchanged() {
+        // The first turn fails on its first token, the retry gets the next two
+        let (mut generation, _) = scripted_generation(PIECES, &["hi", "hi", "<|im_end|>"]);
+        let mut conversation = generation.new_conversation();
+        let mut failing_sink = |_: &str| -> Result<()> { bail!("client went away") };
+        let result = generation.chat(&mut conversation, "a", &request(), &mut failing_sink);
+        assert!(result.is_err());
+        assert_eq!(conversation.messages().len(), 1);
+
+        let output = generation
+            .chat(&mut conversation, "a", &request(), &mut NullSink)
+            .unwrap();
+        assert_eq!(output.text, "hi");
+        let roles: Vec<_> = conversation
+            .messages()
+            .iter()
+            .map(|m| m.role.as_str())
+            .collect();
+        assert_eq!(roles, ["system", "user", "assistant"]);
+    }
+
+    #[test]
+    fn same_seed_reproduces_the_run() {
+        let (mut generation, _) = scripted_generation(PIECES, &["a"]);
+        // Hot enough that the scripted logits leave every token a fair chance
+        let request = GenerationRequest {
+            sampling: Some(SamplingConfig::Temperature { temperature: 100.0 }),
+            max_tokens: Some(16),
+            ..request()
+        };
+        let first = run(&mut generation, "a b", &request);
+        let replay = GenerationRequest {
+            seed: Some(first.seed),
+            ..request
+        };
+        for _ in 0..2 {
+            let output = run(&mut generation, "a b", &replay);
+            assert_eq!(output.seed, first.seed);
+            assert_eq!(output.tokens, first.tokens);
         }
     }
+
+    #[test]
+    fn empty_system_prompt_sends_no_system_message() {
+        // Like Mistral's template: strict user/assistant alternation
+        let template = ChatTemplate::new(
+            "{% for m in messages %}{% if m.role == 'system' %}\
+             {{ raise_exception('Conversation roles must alternate user/assistant') }}\
+             {% endif %}{{ m.content }} {% endfor %}",
+            "",
+            "<|im_end|>",
+        )
+        .unwrap();
+        let (mut generation, _) = scripted_generation(PIECES, &["hi", "<|im_end|>"]);
+        generation.chat_template = Some(template);
+        let messages = [Message::user("a")];
+        assert!(generation.format_messages(&messages, &request()).is_err());
+
+        let mut config = generation.config().clone();
+        config.system_prompt = String::new();
+        generation.set_config(config).unwrap();
+        assert_eq!(
+            generation.format_messages(&messages, &request()).unwrap(),
+            "a "
+        );
+        let mut conversation = generation.new_conversation();
+        let output = generation
+            .chat(&mut conversation, "a", &request(), &mut NullSink)
+            .unwrap();
+        assert_eq!(output.text, "hi");
+    }
+
+    fn run(
+        generation: &mut TextGeneration,
+        prompt: &str,
+        request: &GenerationRequest,
+    ) -> GenerationOutput {
+        generation
+            .stream_raw(prompt, request)
+            .unwrap()
+            .into_output(&mut NullSink)
+            .unwrap()
+    }
 }
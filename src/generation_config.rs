@@ -0,0 +1,167 @@
+//! Generation settings
+//!
+//! `GenerationConfig` gathers everything that shapes a run apart from the
+//! model itself, so it can be read from a file and checked in one place
+//! before a `TextGeneration` is built from it. A `GenerationRequest` overrides
+//! some of those settings for a single run without touching the instance.
+
+use serde::{Deserialize, Serialize};
+
+use crate::sampling::SamplingConfig;
+
+/// Settings applied by `TextGenerationBuilder`; missing fields keep their defaults
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationConfig {
+    pub sampling: SamplingConfig,
+    /// Fixed sampling seed; `None` picks a random one per run
+    pub seed: Option<u64>,
+    /// Penalty applied to the logits of recently generated tokens, 1.0 disables it
+    pub repeat_penalty: f32,
+    /// How many of the last tokens the repeat penalty looks at
+    pub repeat_last_n: usize,
+    /// Maximum number of tokens to generate per run
+    pub max_tokens: usize,
+    /// Maximum tokens inside the `<think>` block before `</think>` is forced
+    pub thinking_budget: Option<usize>,
+    /// Maximum tokens of answer after the `<think>` block
+    pub answer_budget: Option<usize>,
+    pub stop_sequences: Vec<String>,
+    /// Leave empty to send no system message, for chat templates that reject one
+    pub system_prompt: String,
+    pub enable_thinking: bool,
+}
+
+/// Upper bound for `repeat_last_n`, far beyond any useful penalty window
+const MAX_REPEAT_LAST_N: usize = 1 << 16;
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            sampling: SamplingConfig::default(),
+            seed: None,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            max_tokens: 1000,
+            thinking_budget: None,
+            answer_budget: None,
+            stop_sequences: Vec::new(),
+            system_prompt: "You are a helpful assistant".to_string(),
+            enable_thinking: false,
+        }
+    }
+}
+
+impl GenerationConfig {
+    /// Check that every value is within its valid range
+    pub fn validate(&self) -> Result<(), GenerationConfigError> {
+        let sampling = &self.sampling;
+        let temperature = sampling.temperature();
+        if !(temperature.is_finite() && temperature >= 0.0) {
+            return Err(GenerationConfigError::Temperature(temperature));
+        }
+        match *sampling {
+            SamplingConfig::TopK { k, .. } | SamplingConfig::TopKTopP { k, .. } if k == 0 => {
+                return Err(GenerationConfigError::TopK);
+            }
+            _ => {}
+        }
+        match *sampling {
+            SamplingConfig::TopP { p, .. }
+            | SamplingConfig::TopKTopP { p, .. }
+            | SamplingConfig::MinP { p, .. }
+            | SamplingConfig::TypicalP { p, .. }
+                if !(p > 0.0 && p <= 1.0) =>
+            {
+                return Err(GenerationConfigError::Probability(p));
+            }
+            _ => {}
+        }
+        if !(self.repeat_penalty.is_finite() && self.repeat_penalty > 0.0) {
+            return Err(GenerationConfigError::RepeatPenalty(self.repeat_penalty));
+        }
+        if self.repeat_last_n > MAX_REPEAT_LAST_N
+            || (self.repeat_last_n == 0 && self.repeat_penalty != 1.0)
+        {
+            return Err(GenerationConfigError::RepeatLastN(self.repeat_last_n));
+        }
+        if self.max_tokens == 0 {
+            return Err(GenerationConfigError::MaxTokens);
+        }
+        if self.stop_sequences.iter().any(String::is_empty) {
+            return Err(GenerationConfigError::EmptyStopSequence);
+        }
+        Ok(())
+    }
+
+    /// Settings for one run: `request` applied over these defaults, validated
+    pub fn with_request(&self, request: &GenerationRequest) -> Result<Self, GenerationConfigError> {
+        let request = request.clone();
+        let config = Self {
+            sampling: request.sampling.unwrap_or_else(|| self.sampling.clone()),
+            seed: request.seed.or(self.seed),
+            repeat_penalty: request.repeat_penalty.unwrap_or(self.repeat_penalty),
+            repeat_last_n: request.repeat_last_n.unwrap_or(self.repeat_last_n),
+            max_tokens: request.max_tokens.unwrap_or(self.max_tokens),
+            thinking_budget: request.thinking_budget.or(self.thinking_budget),
+            answer_budget: request.answer_budget.or(self.answer_budget),
+            stop_sequences: request
+                .stop_sequences
+                .unwrap_or_else(|| self.stop_sequences.clone()),
+            system_prompt: request
+                .system_prompt
+                .unwrap_or_else(|| self.system_prompt.clone()),
+            enable_thinking: request.enable_thinking.unwrap_or(self.enable_thinking),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// A `GenerationConfig` value outside its valid range
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenerationConfigError {
+    Temperature(f64),
+    TopK,
+    Probability(f64),
+    RepeatPenalty(f32),
+    RepeatLastN(usize),
+    MaxTokens,
+    EmptyStopSequence,
+}
+
+impl std::fmt::Display for GenerationConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Temperature(t) => write!(f, "temperature must be >= 0, got {}", t),
+            Self::TopK => write!(f, "top-k must be at least 1"),
+            Self::Probability(p) => write!(f, "sampling p must be in (0, 1], got {}", p),
+            Self::RepeatPenalty(p) => write!(f, "repeat_penalty must be > 0, got {}", p),
+            Self::RepeatLastN(n) => write!(
+                f,
+                "repeat_last_n must be between 1 and {}, got {}",
+                MAX_REPEAT_LAST_N, n
+            ),
+            Self::MaxTokens => write!(f, "max_tokens must be at least 1"),
+            Self::EmptyStopSequence => write!(f, "stop sequences must not be empty"),
+        }
+    }
+}
+
+impl std::error::Error for GenerationConfigError {}
+
+/// Per-call overrides of a `TextGeneration`'s settings; `None` keeps the instance value
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationRequest {
+    pub sampling: Option<SamplingConfig>,
+    pub seed: Option<u64>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    pub max_tokens: Option<usize>,
+    pub thinking_budget: Option<usize>,
+    pub answer_budget: Option<usize>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub system_prompt: Option<String>,
+    pub enable_thinking: Option<bool>,
+}
@@ -0,0 +1,359 @@
+//! Tokenizer from GGUF metadata
+//!
+//! GGUF files converted by llama.cpp embed the vocabulary under `tokenizer.ggml.*`,
+//! so a single file is enough to run a model without its tokenizer.json. Two
+//! kinds are rebuilt: byte-level BPE (`gpt2`, used by Llama 3, Qwen and SmolLM)
+//! and SentencePiece BPE (`llama`, used by Llama 2, Mistral, Phi-3 and Gemma).
+
+use anyhow::{Result, bail};
+use candle_transformers::models::mimi::candle::quantized::gguf_file;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::byte_level::ByteLevel;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::models::bpe::{BPE, Vocab};
+use tokenizers::normalizers::{Prepend, Replace, Sequence as NormalizerSequence};
+use tokenizers::pre_tokenizers::digits::Digits;
+use tokenizers::pre_tokenizers::sequence::Sequence as PreTokenizerSequence;
+use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+use tokenizers::{AddedToken, SplitDelimiterBehavior, Tokenizer};
+
+/// `tokenizer.ggml.token_type` values, as defined by llama.cpp
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+/// Pre-tokenizer regex of Llama 3 and SmolLM3 (`tokenizer.ggml.pre` = `llama-bpe`)
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+/// Same as Llama 3, but splitting every digit
+const QWEN2_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Build a tokenizer from the `tokenizer.ggml.*` keys of a GGUF header
+pub fn tokenizer_from_gguf(content: &gguf_file::Content) -> Result<Tokenizer> {
+    let metadata = &content.metadata;
+    let Some(tokens) = metadata.get("tokenizer.ggml.tokens") else {
+        bail!("GGUF file has no tokenizer.ggml.tokens");
+    };
+    let tokens = tokens
+        .to_vec()?
+        .iter()
+        .map(|token| Ok(token.to_string()?.clone()))
+        .collect::<Result<Vec<String>>>()?;
+    let token_types = match metadata.get("tokenizer.ggml.token_type") {
+        Some(types) => types
+            .to_vec()?
+            .iter()
+            .map(|t| Ok(t.to_i32()?))
+            .collect::<Result<Vec<i32>>>()?,
+        None => Vec::new(),
+    };
+
+    let model = match metadata.get("tokenizer.ggml.model") {
+        Some(model) => model.to_string()?.as_str(),
+        None => bail!("GGUF file has no tokenizer.ggml.model"),
+    };
+    let mut tokenizer = match model {
+        "gpt2" => byte_level_bpe(content, &tokens)?,
+        "llama" => sentencepiece_bpe(content, &tokens)?,
+        _ => bail!("unsupported GGUF tokenizer {model:?}, expected gpt2 or llama"),
+    };
+
+    // Control tokens (e.g. `<|im_start|>`) and user-defined ones (e.g. `<think>`)
+    // must be matched whole before the model splits the text
+    let added = |kind: i32, special: bool| -> Vec<AddedToken> {
+        tokens
+            .iter()
+            .zip(&token_types)
+            .filter(|&(_, &t)| t == kind)
+            .map(|(token, _)| AddedToken::from(token.clone(), special).normalized(false))
+            .collect()
+    };
+    tokenizer.add_special_tokens(&added(TOKEN_TYPE_CONTROL, true));
+    tokenizer.add_tokens(&added(TOKEN_TYPE_USER_DEFINED, false));
+    Ok(tokenizer)
+}
+
+/// GPT-2 style BPE over bytes mapped to printable characters
+fn byte_level_bpe(content: &gguf_file::Content, tokens: &[String]) -> Result<Tokenizer> {
+    let merges = match content.metadata.get("tokenizer.ggml.merges") {
+        Some(merges) => merges
+            .to_vec()?
+            .iter()
+            .map(|merge| match merge.to_string()?.split_once(' ') {
+                Some((left, right)) => Ok((left.to_string(), right.to_string())),
+                None => bail!("invalid BPE merge {merge:?}"),
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => bail!("GGUF file has no tokenizer.ggml.merges"),
+    };
+    // `tokenizer.ggml.pre` names the pre-tokenizer of the original tokenizer.json
+    let pre = match content.metadata.get("tokenizer.ggml.pre") {
+        Some(pre) => Some(pre.to_string()?.as_str()),
+        None => None,
+    };
+    let (pattern, split_digits, ignore_merges) = match pre {
+        Some("llama-bpe" | "llama3") => (Some(LLAMA3_PATTERN), false, true),
+        Some("qwen2") => (Some(QWEN2_PATTERN), false, false),
+        // SmolLM and SmolLM2: every digit on its own, then the GPT-2 regex
+        Some("smollm") => (None, true, false),
+        None | Some("gpt-2") => (None, false, false),
+        Some(pre) => bail!("unsupported pre-tokenizer {pre:?}, pass --tokenizer"),
+    };
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab(tokens), merges)
+        .ignore_merges(ignore_merges)
+        .build()
+        .map_err(anyhow::Error::msg)?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    match pattern {
+        Some(pattern) => {
+            let split = Split::new(
+                SplitPattern::Regex(pattern.to_string()),
+                SplitDelimiterBehavior::Isolated,
+                false,
+            )
+            .map_err(anyhow::Error::msg)?;
+            tokenizer.with_pre_tokenizer(Some(PreTokenizerSequence::new(vec![
+                split.into(),
+                ByteLevel::new(false, false, false).into(),
+            ])));
+        }
+        // The GPT-2 regex built into the byte-level pre-tokenizer, after the digit split
+        None if split_digits => {
+            tokenizer.with_pre_tokenizer(Some(PreTokenizerSequence::new(vec![
+                Digits::new(true).into(),
+                ByteLevel::new(false, false, true).into(),
+            ])));
+        }
+        None => {
+            tokenizer.with_pre_tokenizer(Some(ByteLevel::new(false, false, true)));
+        }
+    }
+    tokenizer.with_decoder(Some(ByteLevel::default()));
+    Ok(tokenizer)
+}
+
+/// SentencePiece BPE: spaces become `▁`, unknown bytes fall back to `<0xNN>` tokens.
+/// GGUF stores piece scores instead of merges, so the merges are rebuilt from them.
+fn sentencepiece_bpe(content: &gguf_file::Content, tokens: &[String]) -> Result<Tokenizer> {
+    let metadata = &content.metadata;
+    let scores = match metadata.get("tokenizer.ggml.scores") {
+        Some(scores) => scores
+            .to_vec()?
+            .iter()
+            .map(|score| Ok(score.to_f32()?))
+            .collect::<Result<Vec<f32>>>()?,
+        None => vec![0.0; tokens.len()],
+    };
+    let vocab = vocab(tokens);
+
+    // Every split of a piece into two known pieces is a merge, applied in the order
+    // of the merged piece's score
+    let mut merges = Vec::new();
+    for (id, token) in tokens.iter().enumerate() {
+        for (split, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(split);
+            if let (Some(&left_id), Some(&right_id)) = (vocab.get(left), vocab.get(right)) {
+                merges.push((id, left_id, right_id, left.to_string(), right.to_string()));
+            }
+        }
+    }
+    let score = |id: usize| scores.get(id).copied().unwrap_or(0.0);
+    merges.sort_by(|a, b| {
+        score(b.0)
+            .total_cmp(&score(a.0))
+            .then((a.1, a.2).cmp(&(b.1, b.2)))
+    });
+    let merges = merges
+        .into_iter()
+        .map(|(_, _, _, left, right)| (left, right))
+        .collect();
+
+    let mut builder = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .byte_fallback(true)
+        .fuse_unk(true);
+    let unk_id = metadata
+        .get("tokenizer.ggml.unknown_token_id")
+        .and_then(|id| id.to_u32().ok())
+        .unwrap_or(0);
+    if let Some(unk) = tokens.get(unk_id as usize) {
+        builder = builder.unk_token(unk.clone());
+    }
+    let bpe = builder.build().map_err(anyhow::Error::msg)?;
+
+    // Gemma does not prefix the text with a space
+    let add_space_prefix = metadata
+        .get("tokenizer.ggml.add_space_prefix")
+        .and_then(|value| value.to_bool().ok())
+        .unwrap_or(true);
+    let replace = Replace::new(" ", "▁").map_err(anyhow::Error::msg)?;
+    let mut tokenizer = Tokenizer::new(bpe);
+    let mut decoders = vec![
+        Replace::new("▁", " ").map_err(anyhow::Error::msg)?.into(),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+    ];
+    if add_space_prefix {
+        tokenizer.with_normalizer(Some(NormalizerSequence::new(vec![
+            Prepend::new("▁".to_string()).into(),
+            replace.into(),
+        ])));
+        decoders.push(Strip::new(' ', 1, 0).into());
+    } else {
+        tokenizer.with_normalizer(Some(replace));
+    }
+    tokenizer.with_decoder(Some(DecoderSequence::new(decoders)));
+    Ok(tokenizer)
+}
+
+fn vocab(tokens: &[String]) -> Vocab {
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), id as u32))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gguf_file::Value;
+
+    fn strings(values: &[&str]) -> Value {
+        Value::Array(
+            values
+                .iter()
+                .map(|v| Value::String(v.to_string()))
+                .collect(),
+        )
+    }
+
+    fn header(metadata: Vec<(&str, Value)>) -> gguf_file::Content {
+        gguf_file::Content {
+            magic: gguf_file::VersionedMagic::GgufV3,
+            metadata: metadata
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            tensor_infos: Default::default(),
+            tensor_data_offset: 0,
+        }
+    }
+
+    fn encode(tokenizer: &Tokenizer, text: &str) -> Vec<u32> {
+        tokenizer.encode(text, false).unwrap().get_ids().to_vec()
+    }
+
+    #[test]
+    fn byte_level_bpe_vocabulary() {
+        let tokens = [
+            "h",
+            "e",
+            "l",
+            "o",
+            "Ġ",
+            "he",
+            "ll",
+            "hell",
+            "hello",
+            "Ġhello",
+            "<|im_start|>",
+            "<think>",
+        ];
+        let mut types = vec![Value::I32(1); tokens.len()];
+        types[10] = Value::I32(TOKEN_TYPE_CONTROL);
+        types[11] = Value::I32(TOKEN_TYPE_USER_DEFINED);
+        let content = header(vec![
+            ("tokenizer.ggml.model", Value::String("gpt2".to_string())),
+            ("tokenizer.ggml.pre", Value::String("qwen2".to_string())),
+            ("tokenizer.ggml.tokens", strings(&tokens)),
+            ("tokenizer.ggml.token_type", Value::Array(types)),
+            (
+                "tokenizer.ggml.merges",
+                strings(&["h e", "l l", "he ll", "hell o", "Ġ hello"]),
+            ),
+        ]);
+        let tokenizer = tokenizer_from_gguf(&content).unwrap();
+
+        let ids = encode(&tokenizer, "<|im_start|>hello hello<think>");
+        assert_eq!(ids, [10, 8, 9, 11]);
+        assert_eq!(tokenizer.decode(&ids, true).unwrap(), "hello hello<think>");
+    }
+
+    /// Byte-level BPE where `12`, `123` and `,b` have merges but `ab` only a vocabulary entry
+    fn pre_tokenized(pre: Option<&str>, text: &str) -> Result<Vec<u32>> {
+        let tokens = ["a", "b", ",", "1", "2", "3", "12", "123", ",b", "ab"];
+        let mut metadata = vec![
+            ("tokenizer.ggml.model", Value::String("gpt2".to_string())),
+            ("tokenizer.ggml.tokens", strings(&tokens)),
+            ("tokenizer.ggml.merges", strings(&["1 2", "12 3", ", b"])),
+        ];
+        if let Some(pre) = pre {
+            metadata.push(("tokenizer.ggml.pre", Value::String(pre.to_string())));
+        }
+        Ok(encode(&tokenizer_from_gguf(&header(metadata))?, text))
+    }
+
+    #[test]
+    fn pre_tokenizer_types() {
+        // Punctuation joins the next word, up to three digits together, whole-word lookup
+        assert_eq!(
+            pre_tokenized(Some("llama-bpe"), "ab,b123").unwrap(),
+            [9, 8, 7]
+        );
+        // Same regex, but one digit at a time
+        assert_eq!(
+            pre_tokenized(Some("qwen2"), "ab,b123").unwrap(),
+            [0, 1, 8, 3, 4, 5]
+        );
+        // GPT-2 regex after splitting digits: punctuation stands alone
+        assert_eq!(
+            pre_tokenized(Some("smollm"), "ab,b123").unwrap(),
+            [0, 1, 2, 1, 3, 4, 5]
+        );
+        assert_eq!(
+            pre_tokenized(Some("gpt-2"), "ab,b123").unwrap(),
+            [0, 1, 2, 1, 7]
+        );
+        assert_eq!(pre_tokenized(None, "ab,b123").unwrap(), [0, 1, 2, 1, 7]);
+        let err = pre_tokenized(Some("deepseek-llm"), "ab").unwrap_err();
+        assert!(err.to_string().contains("--tokenizer"), "{err}");
+    }
+
+    #[test]
+    fn sentencepiece_vocabulary() {
+        let tokens = ["<unk>", "<s>", "</s>", "<0x21>", "▁", "h", "i", "hi", "▁hi"];
+        let mut types = vec![Value::I32(1); tokens.len()];
+        types[0] = Value::I32(2);
+        types[1] = Value::I32(TOKEN_TYPE_CONTROL);
+        types[2] = Value::I32(TOKEN_TYPE_CONTROL);
+        types[3] = Value::I32(6);
+        let scores = [0.0, 0.0, 0.0, 0.0, -1.0, -2.0, -3.0, -4.0, -5.0];
+        let content = header(vec![
+            ("tokenizer.ggml.model", Value::String("llama".to_string())),
+            ("tokenizer.ggml.tokens", strings(&tokens)),
+            ("tokenizer.ggml.token_type", Value::Array(types)),
+            (
+                "tokenizer.ggml.scores",
+                Value::Array(scores.into_iter().map(Value::F32).collect()),
+            ),
+        ]);
+        let tokenizer = tokenizer_from_gguf(&content).unwrap();
+
+        // The space prefix makes both words `▁hi`, and `!` falls back to its byte token
+        let ids = encode(&tokenizer, "<s>hi hi!");
+        assert_eq!(ids, [1, 8, 8, 3]);
+        assert_eq!(tokenizer.decode(&ids, true).unwrap(), "hi hi!");
+    }
+
+    #[test]
+    fn unsupported_tokenizer_model() {
+        let content = header(vec![
+            ("tokenizer.ggml.model", Value::String("bert".to_string())),
+            ("tokenizer.ggml.tokens", strings(&["a"])),
+        ]);
+        assert!(tokenizer_from_gguf(&content).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Offline lookup in the HuggingFace hub cache
+//!
+//! Files downloaded by `huggingface-cli` or any other hf-hub based tool live in
+//! `~/.cache/huggingface/hub/models--{org}--{name}/snapshots/{commit}`, and
+//! `refs/{branch}` holds the commit each branch pointed to when it was fetched.
+//! A repo id such as `HuggingFaceTB/SmolLM3-3B` is resolved against that layout,
+//! without network access, so models are shared with other tools instead of
+//! being copied next to llm-rs.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+
+/// Revision used when none is given
+pub const DEFAULT_REVISION: &str = "main";
+
+/// The hub cache: `$HF_HUB_CACHE`, `$HF_HOME/hub` or `~/.cache/huggingface/hub`
+pub fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("HF_HUB_CACHE") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(home) = std::env::var_os("HF_HOME") {
+        return Some(PathBuf::from(home).join("hub"));
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(
+        PathBuf::from(home)
+            .join(".cache")
+            .join("huggingface")
+            .join("hub"),
+    )
+}
+
+/// Snapshot directory of `repo_id` at `revision`, a branch, tag or commit hash
+pub fn snapshot_dir(repo_id: &str, revision: Option<&str>) -> Result<PathBuf> {
+    cached_snapshot(cache_dir().as_deref(), repo_id, revision)
+}
+
+/// Like `snapshot_dir`, in the hub cache at `cache`
+pub fn snapshot_dir_in(cache: &Path, repo_id: &str, revision: Option<&str>) -> Result<PathBuf> {
+    let repo_dir = cache.join(format!("models--{}", repo_id.replace('/', "--")));
+    if !repo_dir.is_dir() {
+        bail!(
+            "{repo_id} is not in the HuggingFace cache at {}",
+            cache.display()
+        );
+    }
+
+    let revision = revision.unwrap_or(DEFAULT_REVISION);
+    let ref_path = repo_dir.join("refs").join(revision);
+    let commit = if ref_path.is_file() {
+        std::fs::read_to_string(&ref_path)?.trim().to_string()
+    } else {
+        revision.to_string()
+    };
+    let snapshot = repo_dir.join("snapshots").join(&commit);
+    if !snapshot.is_dir() {
+        bail!("revision {revision:?} of {repo_id} is not in the HuggingFace cache");
+    }
+    Ok(snapshot)
+}
+
+/// Resolve a model given as a local path or as a cached repo: `org/name` or
+/// `org/name/file.gguf`. A repo without a file name resolves to its safetensors
+/// snapshot, or to its only GGUF file.
+pub fn resolve_model(model: &str, revision: Option<&str>) -> Result<PathBuf> {
+    resolve_model_in(cache_dir().as_deref(), model, revision)
+}
+
+fn resolve_model_in(cache: Option<&Path>, model: &str, revision: Option<&str>) -> Result<PathBuf> {
+    let path = Path::new(model);
+    if path.exists() {
+        return Ok(path.to_path_buf());
+    }
+    let Some((repo_id, file)) = split_repo_id(path) else {
+        bail!("model {model:?} does not exist");
+    };
+    let snapshot = cached_snapshot(cache, &repo_id, revision)
+        .with_context(|| format!("model {model:?} is neither a local path nor a cached repo"))?;
+
+    if let Some(file) = file {
+        return cached_file(&repo_id, &snapshot, &file);
+    }
+
+    let mut gguf_files = Vec::new();
+    let mut has_safetensors = false;
+    for entry in std::fs::read_dir(&snapshot)? {
+        let path = entry?.path();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gguf") => gguf_files.push(path),
+            Some("safetensors") => has_safetensors = true,
+            _ => {}
+        }
+    }
+    if has_safetensors && snapshot.join("config.json").exists() {
+        return Ok(snapshot);
+    }
+    gguf_files.sort();
+    match gguf_files.as_slice() {
+        [file] => Ok(file.clone()),
+        [] => bail!("{repo_id} has no GGUF or safetensors model in the HuggingFace cache"),
+        files => {
+            let names: Vec<_> = files
+                .iter()
+                .filter_map(|file| file.file_name())
+                .map(|name| name.to_string_lossy())
+                .collect();
+            bail!(
+                "{repo_id} has several GGUF files, pick one as {repo_id}/<file>: {}",
+                names.join(", ")
+            )
+        }
+    }
+}
+
+/// Resolve a file such as tokenizer.json given as a local path or as `org/name[/file]`,
+/// at the repo's `main` revision. A directory or a repo without a file name resolves to
+/// its `default_file`.
+pub fn resolve_file(path: &str, default_file: &str) -> Result<PathBuf> {
+    resolve_file_in(cache_dir().as_deref(), path, default_file)
+}
+
+fn resolve_file_in(cache: Option<&Path>, path: &str, default_file: &str) -> Result<PathBuf> {
+    let local = Path::new(path);
+    if local.is_dir() {
+        return Ok(local.join(default_file));
+    }
+    if local.exists() {
+        return Ok(local.to_path_buf());
+    }
+    let Some((repo_id, file)) = split_repo_id(local) else {
+        bail!("{path:?} does not exist");
+    };
+    let snapshot = cached_snapshot(cache, &repo_id, None)
+        .with_context(|| format!("{path:?} is neither a local path nor a cached repo"))?;
+    cached_file(
+        &repo_id,
+        &snapshot,
+        &file.unwrap_or_else(|| PathBuf::from(default_file)),
+    )
+}
+
+fn cached_snapshot(cache: Option<&Path>, repo_id: &str, revision: Option<&str>) -> Result<PathBuf> {
+    let cache = cache.context("cannot locate the HuggingFace cache, set HF_HOME")?;
+    snapshot_dir_in(cache, repo_id, revision)
+}
+
+fn cached_file(repo_id: &str, snapshot: &Path, file: &Path) -> Result<PathBuf> {
+    let path = snapshot.join(file);
+    if !path.exists() {
+        bail!(
+            "{repo_id} has no file {} in the HuggingFace cache",
+            file.display()
+        );
+    }
+    Ok(path)
+}
+
+/// Split `org/name[/file]` into the repo id and the path of the file inside the repo
+fn split_repo_id(path: &Path) -> Option<(String, Option<PathBuf>)> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_str()?),
+            _ => return None,
+        }
+    }
+    match components.as_slice() {
+        [org, name] => Some((format!("{org}/{name}"), None)),
+        [org, name, file @ ..] => Some((format!("{org}/{name}"), Some(file.iter().collect()))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty hub cache in the temporary directory, unique to `name`
+    fn cache(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("llm-rs-hub-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Create `files` in the `commit` snapshot of `repo_id`, with `refs/main` pointing to it
+    fn repo(cache: &Path, repo_id: &str, commit: &str, files: &[&str]) -> PathBuf {
+        let repo_dir = cache.join(format!("models--{}", repo_id.replace('/', "--")));
+        let snapshot = repo_dir.join("snapshots").join(commit);
+        std::fs::create_dir_all(&snapshot).unwrap();
+        std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+        std::fs::write(repo_dir.join("refs").join("main"), format!("{commit}\n")).unwrap();
+        for file in files {
+            std::fs::write(snapshot.join(file), "").unwrap();
+        }
+        snapshot
+    }
+
+    #[test]
+    fn branch_resolves_through_refs() {
+        let cache = cache("refs");
+        let snapshot = repo(&cache, "org/gguf", "abc123", &["model.gguf"]);
+        assert_eq!(snapshot_dir_in(&cache, "org/gguf", None).unwrap(), snapshot);
+        assert_eq!(
+            resolve_model_in(Some(&cache), "org/gguf", None).unwrap(),
+            snapshot.join("model.gguf")
+        );
+        assert!(resolve_model_in(Some(&cache), "org/gguf", Some("dev")).is_err());
+        assert!(resolve_model_in(None, "org/gguf", None).is_err());
+    }
+
+    #[test]
+    fn commit_revision_names_the_snapshot() {
+        let cache = cache("commit");
+        let main = repo(&cache, "org/revisions", "abc123", &["model.gguf"]);
+        let old = main.with_file_name("def456");
+        std::fs::create_dir_all(&old).unwrap();
+        std::fs::write(old.join("model.gguf"), "").unwrap();
+        assert_eq!(
+            snapshot_dir_in(&cache, "org/revisions", None).unwrap(),
+            main
+        );
+        assert_eq!(
+            resolve_model_in(Some(&cache), "org/revisions", Some("def456")).unwrap(),
+            old.join("model.gguf")
+        );
+        assert!(resolve_model_in(Some(&cache), "org/revisions", Some("0000000")).is_err());
+    }
+
+    #[test]
+    fn several_gguf_files_need_a_file_name() {
+        let cache = cache("quants");
+        let snapshot = repo(&cache, "org/quants", "abc123", &["q4.gguf", "q8.gguf"]);
+        let err = resolve_model_in(Some(&cache), "org/quants", None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("q4.gguf, q8.gguf"), "{err}");
+        assert_eq!(
+            resolve_model_in(Some(&cache), "org/quants/q8.gguf", None).unwrap(),
+            snapshot.join("q8.gguf")
+        );
+        assert!(resolve_model_in(Some(&cache), "org/quants/q2.gguf", None).is_err());
+    }
+
+    #[test]
+    fn safetensors_take_precedence_over_gguf() {
+        let cache = cache("safetensors");
+        let files = ["config.json", "model.safetensors", "model.gguf"];
+        let snapshot = repo(&cache, "org/both", "abc123", &files);
+        assert_eq!(
+            resolve_model_in(Some(&cache), "org/both", None).unwrap(),
+            snapshot
+        );
+    }
+
+    #[test]
+    fn tokenizer_resolves_in_the_cache() {
+        let cache = cache("tokenizer");
+        let files = ["tokenizer.json", "model.gguf"];
+        let snapshot = repo(&cache, "org/tokenizer", "abc123", &files);
+        assert_eq!(
+            resolve_file_in(Some(&cache), "org/tokenizer", "tokenizer.json").unwrap(),
+            snapshot.join("tokenizer.json")
+        );
+        assert_eq!(
+            resolve_file_in(None, snapshot.to_str().unwrap(), "tokenizer.json").unwrap(),
+            snapshot.join("tokenizer.json")
+        );
+        assert!(resolve_file_in(Some(&cache), "org/missing", "tokenizer.json").is_err());
+    }
+}
@@ -1,20 +1,51 @@
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 
+use crate::chat_template::{ChatTemplate, ChatTemplateError};
+use crate::config::Config;
 use crate::generation::TextGeneration;
-use anyhow::{Error as E, Result};
-use candle_transformers::models::mimi::candle::Device;
-use candle_transformers::models::smol::quantized_smollm3::QuantizedModelForCausalLM;
+use crate::generation_config::{GenerationConfig, GenerationRequest};
+use crate::model::Model;
+use crate::sink::WriterSink;
+use anyhow::{Context, Error as E, Result, bail};
+use candle_transformers::models::mimi::candle::quantized::gguf_file;
+use candle_transformers::models::mimi::candle::{DType, Device};
 use tokenizers::Tokenizer;
 
-mod chat_template;
+#[cfg(feature = "async")]
+pub mod async_generation;
+pub mod chat_template;
+pub mod config;
 pub mod generation;
+pub mod generation_config;
+pub mod gguf_tokenizer;
+pub mod hub;
+pub mod model;
+pub mod sampling;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod sink;
+#[cfg(test)]
+mod testing;
 mod tokenizer;
 
 pub struct ModelArgs {
+    /// A GGUF file, a directory with config.json and safetensors weights, or a
+    /// repo in the HuggingFace cache, see `hub::resolve_model`
     pub model_path: String,
-    pub system_prompt: Option<String>,
-    pub enable_thinking: Option<bool>,
+    /// Branch, tag or commit of the cached model repo; defaults to `main`
+    pub revision: Option<String>,
+    /// Device to run on, see `select_device`
+    pub device: String,
+    /// Weight type of a safetensors model, see `select_dtype`
+    pub dtype: Option<String>,
+    /// tokenizer.json or a cached repo that has one, at its `main` revision; defaults to
+    /// tokenizer.json next to the model, or the vocabulary embedded in a GGUF file
+    pub tokenizer_path: Option<String>,
+    /// Name of the chat template to use when the model ships several (e.g. "tool_use")
+    pub chat_template: Option<String>,
+    pub generation: GenerationConfig,
     pub interrupt_signal: Arc<AtomicBool>,
 }
 impl ModelArgs {
@@ -24,43 +55,175 @@ impl ModelArgs {
         enable_thinking: Option<bool>,
         interrupt_signal: Arc<AtomicBool>,
     ) -> Self {
+        let mut generation = GenerationConfig::default();
+        if let Some(system_prompt) = system_prompt {
+            generation.system_prompt = system_prompt;
+        }
+        if let Some(enable_thinking) = enable_thinking {
+            generation.enable_thinking = enable_thinking;
+        }
         Self {
             model_path,
-            system_prompt,
-            enable_thinking,
+            revision: None,
+            device: "auto".to_string(),
+            dtype: None,
+            tokenizer_path: None,
+            chat_template: None,
+            generation,
             interrupt_signal,
         }
     }
 }
 
-pub fn run() -> Result<(), E> {
-    let prompt = "Rust is a great language";
+/// Load the model described by `config` and print its answer to `prompt`
+pub fn run(config: &Config, prompt: &str) -> Result<(), E> {
     let interrupt_signal = Arc::new(AtomicBool::new(false));
-    let args = ModelArgs::new("model/llm.gguf".to_string(), None, None, interrupt_signal);
-    let mut generation_model = setup(args, &Device::cuda_if_available(0)?)?;
-    generation_model.run_generation(prompt)?;
+    let args = config.model_args(interrupt_signal);
+    let mut generation_model = setup(args)?;
+    generation_model.run_generation_with_sink(
+        prompt,
+        &GenerationRequest::default(),
+        &mut WriterSink::stdout(),
+    )?;
+    println!();
 
     Ok(())
 }
 
-pub fn setup(args: ModelArgs, device: &Device) -> Result<TextGeneration> {
-    let tokenizer_filename = std::path::PathBuf::from("model/tokenizer.json");
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-
-    let model_path = std::path::PathBuf::from(args.model_path);
-    let model = QuantizedModelForCausalLM::from_gguf(model_path, &device)?;
-
-    Ok(TextGeneration::new(
-        &device,
-        model,
-        tokenizer,
-        None,
-        None,
-        None,
-        None,
-        None,
-        args.system_prompt,
-        args.enable_thinking,
-        args.interrupt_signal,
-    ))
+/// Pick the device named by `name`: `cpu`, `cuda[:N]`, `metal[:N]`, or `auto` for the
+/// first GPU backend compiled in (CUDA, then Metal) with a fallback to the CPU
+pub fn select_device(name: &str) -> Result<Device> {
+    let (kind, ordinal) = match name.split_once(':') {
+        Some((kind, ordinal)) => (kind, ordinal.parse().context("invalid device ordinal")?),
+        None => (name, 0),
+    };
+    let device = match kind {
+        "auto" if cfg!(feature = "cuda") => Device::cuda_if_available(ordinal)?,
+        "auto" if cfg!(feature = "metal") => Device::metal_if_available(ordinal)?,
+        "auto" | "cpu" => Device::Cpu,
+        "cuda" if cfg!(feature = "cuda") => Device::new_cuda(ordinal)?,
+        "metal" if cfg!(feature = "metal") => Device::new_metal(ordinal)?,
+        "cuda" | "metal" => bail!("device {name:?} requires building with the `{kind}` feature"),
+        _ => bail!("unknown device {name:?}, expected auto, cpu, cuda[:N] or metal[:N]"),
+    };
+    Ok(device)
+}
+
+/// Pick the weight type named by `name`: `f32`, `bf16` or `f16`. Defaults to bf16 on
+/// GPUs and f32 on the CPU.
+pub fn select_dtype(name: Option<&str>, device: &Device) -> Result<DType> {
+    let dtype = match name {
+        None if device.is_cpu() => DType::F32,
+        None => DType::BF16,
+        Some("f32") => DType::F32,
+        Some("bf16") => DType::BF16,
+        Some("f16") => DType::F16,
+        Some(name) => bail!("unknown dtype {name:?}, expected f32, bf16 or f16"),
+    };
+    Ok(dtype)
+}
+
+/// Load the model at `args.model_path`: a GGUF file, or a HuggingFace directory holding
+/// config.json, the safetensors weights and tokenizer.json, either local or in the hub cache
+pub fn setup(args: ModelArgs) -> Result<TextGeneration> {
+    Ok(setup_with_header(args)?.0)
+}
+
+/// Like `setup`, also returning the header of a GGUF model so that callers can describe
+/// the model without reading the file again
+pub fn setup_with_header(args: ModelArgs) -> Result<(TextGeneration, Option<gguf_file::Content>)> {
+    let device = &select_device(&args.device)?;
+    let model_path = hub::resolve_model(&args.model_path, args.revision.as_deref())?;
+    let (model, gguf) = if model_path.is_dir() {
+        let dtype = select_dtype(args.dtype.as_deref(), device)?;
+        (Model::from_safetensors(&model_path, dtype, device)?, None)
+    } else {
+        let mut file = std::fs::File::open(&model_path)?;
+        let gguf = gguf_file::Content::read(&mut file)?;
+        (Model::from_gguf(&model_path, &gguf, device)?, Some(gguf))
+    };
+
+    // Without an explicit path, tokenizer.json next to the model, else the GGUF vocabulary
+    let tokenizer_filename = match &args.tokenizer_path {
+        Some(path) => hub::resolve_file(path, "tokenizer.json")?,
+        None if model_path.is_dir() => model_path.join("tokenizer.json"),
+        None => model_path.with_file_name("tokenizer.json"),
+    };
+    let tokenizer = match &gguf {
+        Some(gguf) if args.tokenizer_path.is_none() && !tokenizer_filename.exists() => {
+            gguf_tokenizer::tokenizer_from_gguf(gguf)?
+        }
+        _ => Tokenizer::from_file(&tokenizer_filename).map_err(E::msg)?,
+    };
+    let chat_template = load_chat_template(
+        &tokenizer_filename,
+        gguf.as_ref(),
+        args.chat_template.as_deref(),
+    )?;
+
+    let mut generation = TextGeneration::builder(model, tokenizer, device)
+        .config(args.generation)
+        .chat_template(chat_template)
+        .interrupt_signal(args.interrupt_signal)
+        .build()?;
+
+    let mut eos_token_ids = gguf.as_ref().map(gguf_eos_token_ids).unwrap_or_default();
+    eos_token_ids.extend(generation_config_eos_token_ids(
+        &tokenizer_filename.with_file_name("generation_config.json"),
+    )?);
+    if let Some(template) = &generation.chat_template {
+        eos_token_ids.extend(generation.tokenizer.get_token(template.eos_token()));
+    }
+    generation.add_eos_token_ids(eos_token_ids);
+    Ok((generation, gguf))
+}
+
+/// Find the model's own chat template: tokenizer_config.json next to the tokenizer first,
+/// then the GGUF header if there is one. Returns `None` when neither provides one.
+fn load_chat_template(
+    tokenizer_path: &Path,
+    gguf: Option<&gguf_file::Content>,
+    name: Option<&str>,
+) -> Result<Option<ChatTemplate>> {
+    let config_path = tokenizer_path.with_file_name("tokenizer_config.json");
+    if config_path.exists() {
+        match ChatTemplate::from_tokenizer_config_named(&config_path, name) {
+            Ok(template) => return Ok(Some(template)),
+            Err(ChatTemplateError::NoTemplate) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let result = match gguf {
+        Some(gguf) => ChatTemplate::from_gguf(gguf, name),
+        None => Err(ChatTemplateError::NoTemplate),
+    };
+    match result {
+        Ok(template) => Ok(Some(template)),
+        Err(ChatTemplateError::NoTemplate) if name.is_none() => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// End-of-sequence and end-of-turn ids declared in the GGUF header
+fn gguf_eos_token_ids(gguf: &gguf_file::Content) -> Vec<u32> {
+    ["tokenizer.ggml.eos_token_id", "tokenizer.ggml.eot_token_id"]
+        .iter()
+        .filter_map(|key| gguf.metadata.get(*key))
+        .filter_map(|value| value.to_u32().ok())
+        .collect()
+}
+
+/// `eos_token_id` from a HuggingFace generation_config.json, which may be a single id or a list
+fn generation_config_eos_token_ids(path: &Path) -> Result<Vec<u32>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let ids = match &config["eos_token_id"] {
+        serde_json::Value::Number(id) => id.as_u64().into_iter().collect(),
+        serde_json::Value::Array(ids) => ids.iter().filter_map(|id| id.as_u64()).collect(),
+        _ => Vec::new(),
+    };
+    Ok(ids.into_iter().map(|id: u64| id as u32).collect())
 }
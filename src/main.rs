@@ -0,0 +1,319 @@
+//! Command-line interface
+//!
+//! `llm-rs chat` keeps a multi-turn conversation with the model. Lines starting
+//! with `/` are commands, see `/help`; Ctrl-C stops the current answer.
+//! `llm-rs generate <prompt>` prints a single answer.
+//!
+//! Flags override the config file and `LLM_RS_*` variables, see `llm_rs::config`.
+
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use llm_rs::chat_template::{Conversation, Message};
+use llm_rs::config::Config;
+use llm_rs::generation::{FinishReason, TextGeneration};
+use llm_rs::generation_config::GenerationRequest;
+use llm_rs::sink::TokenSink;
+use llm_rs::{run, setup};
+
+const HELP: &str = "\
+/reset           forget the conversation
+/system <text>   set the system prompt and start over
+/temp <value>    set the sampling temperature
+/save <file>     write the conversation to a JSON file
+/load <file>     continue a conversation from a JSON file
+/think on|off    turn reasoning on or off
+/help            show this message
+/exit            quit (or Ctrl-D)";
+
+#[derive(Parser)]
+#[command(name = "llm-rs", about = "Run a local language model")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Chat with the model interactively
+    Chat,
+    /// Print the answer to a single prompt
+    Generate { prompt: String },
+}
+
+#[derive(clap::Args)]
+struct Options {
+    /// TOML or JSON config file [default: llm-rs.toml if present]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Path to the GGUF model or a safetensors model directory, or a repo id
+    /// in the HuggingFace cache, optionally followed by a file: org/name[/file.gguf]
+    #[arg(long, global = true)]
+    model: Option<String>,
+    /// Branch, tag or commit of the cached model repo [default: main]
+    #[arg(long, global = true)]
+    revision: Option<String>,
+    /// Path to tokenizer.json or a cached repo at its main revision [default: next to the
+    /// model, else from the GGUF file]
+    #[arg(long, global = true)]
+    tokenizer: Option<String>,
+    /// auto, cpu, cuda[:N] or metal[:N]
+    #[arg(long, global = true)]
+    device: Option<String>,
+    /// Weight type of a safetensors model: f32, bf16 or f16
+    #[arg(long, global = true)]
+    dtype: Option<String>,
+    #[arg(long, global = true)]
+    temperature: Option<f64>,
+    #[arg(long, global = true)]
+    top_p: Option<f64>,
+    #[arg(long, global = true)]
+    top_k: Option<usize>,
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+    /// Maximum number of tokens to generate
+    #[arg(long, global = true)]
+    max_tokens: Option<usize>,
+    #[arg(long, global = true)]
+    system: Option<String>,
+    /// Let the model reason before answering; `--think=false` turns it off
+    #[arg(long, global = true, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    think: Option<bool>,
+}
+
+impl Options {
+    fn into_config(self) -> Result<Config> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(model) = self.model {
+            config.model = model;
+        }
+        if self.revision.is_some() {
+            config.revision = self.revision;
+        }
+        if self.tokenizer.is_some() {
+            config.tokenizer = self.tokenizer;
+        }
+        if let Some(device) = self.device {
+            config.device = device;
+        }
+        if self.dtype.is_some() {
+            config.dtype = self.dtype;
+        }
+        if self.seed.is_some() {
+            config.generation.seed = self.seed;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            config.generation.max_tokens = max_tokens;
+        }
+        if let Some(system) = self.system {
+            config.generation.system_prompt = system;
+        }
+        if let Some(think) = self.think {
+            config.generation.enable_thinking = think;
+        }
+        config.override_sampling(self.temperature, self.top_p, self.top_k);
+        Ok(config)
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = cli.options.into_config()?;
+    match cli.command {
+        Command::Chat => chat(&config),
+        Command::Generate { prompt } => run(&config, &prompt),
+    }
+}
+
+fn chat(config: &Config) -> Result<()> {
+    let interrupt_signal = Arc::new(AtomicBool::new(false));
+    let args = config.model_args(interrupt_signal.clone());
+    let mut generation = setup(args)?;
+    // Installed once the model is loaded, so Ctrl-C still aborts a slow load
+    ctrlc::set_handler(move || interrupt_signal.store(true, Ordering::Relaxed))?;
+    let mut conversation = generation.new_conversation();
+
+    println!("Type /help for commands, Ctrl-C to stop an answer, Ctrl-D to quit.");
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next() else {
+            println!();
+            return Ok(());
+        };
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(command) = line.strip_prefix('/') {
+            match run_command(&mut generation, &mut conversation, command) {
+                Ok(true) => continue,
+                Ok(false) => return Ok(()),
+                Err(err) => {
+                    eprintln!("error: {err:#}");
+                    continue;
+                }
+            }
+        }
+
+        let output = match generation.chat(
+            &mut conversation,
+            line,
+            &GenerationRequest::default(),
+            &mut ChatSink::default(),
+        ) {
+            Ok(output) => output,
+            // The turn was dropped from the conversation, so the user can retry it
+            Err(err) => {
+                println!();
+                eprintln!("error: {err:#}");
+                continue;
+            }
+        };
+        if output.finish_reason == FinishReason::Interrupted {
+            print!(" [interrupted]");
+        }
+        println!();
+    }
+}
+
+/// Run a slash command; returns `false` when the REPL should exit
+fn run_command(
+    generation: &mut TextGeneration,
+    conversation: &mut Conversation,
+    command: &str,
+) -> Result<bool> {
+    let (name, arg) = command
+        .split_once(char::is_whitespace)
+        .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+    match name {
+        "exit" | "quit" => return Ok(false),
+        "help" => println!("{HELP}"),
+        "reset" => {
+            generation.clear_cache();
+            conversation.clear();
+        }
+        "system" => {
+            if arg.is_empty() {
+                bail!("usage: /system <text>");
+            }
+            let mut config = generation.config().clone();
+            config.system_prompt = arg.to_string();
+            generation.set_config(config)?;
+            generation.clear_cache();
+            *conversation = generation.new_conversation();
+        }
+        "temp" => {
+            let temperature: f64 = arg.parse().context("usage: /temp <value>")?;
+            let mut config = generation.config().clone();
+            config.sampling = config.sampling.with_temperature(temperature);
+            generation.set_config(config)?;
+        }
+        "save" => {
+            if arg.is_empty() {
+                bail!("usage: /save <file>");
+            }
+            let json = serde_json::to_string_pretty(&history(generation, conversation))?;
+            std::fs::write(arg, json)?;
+        }
+        "load" => {
+            if arg.is_empty() {
+                bail!("usage: /load <file>");
+            }
+            let json = std::fs::read_to_string(PathBuf::from(arg))?;
+            let messages: Vec<Message> = serde_json::from_str(&json)?;
+            *conversation = restore(generation, &messages)?;
+        }
+        "think" => {
+            let mut config = generation.config().clone();
+            config.enable_thinking = match arg {
+                "on" => true,
+                "off" => false,
+                _ => bail!("usage: /think on|off"),
+            };
+            generation.set_config(config)?;
+            // The template options, and for ChatML the system block, depend on the mode
+            let messages = history(generation, conversation);
+            *conversation = restore(generation, &messages)?;
+        }
+        _ => bail!("unknown command /{name}, see /help"),
+    }
+    Ok(true)
+}
+
+/// Messages of `conversation` with the plain system prompt, which the built-in
+/// ChatML template would otherwise show wrapped in its metadata block
+fn history(generation: &TextGeneration, conversation: &Conversation) -> Vec<Message> {
+    let mut messages = conversation.messages().to_vec();
+    if let Some(first) = messages.first_mut()
+        && first.role == "system"
+    {
+        first.content = generation.config().system_prompt.clone();
+    }
+    messages
+}
+
+/// Start a conversation holding `messages`; a leading system message replaces the system prompt
+fn restore(generation: &mut TextGeneration, messages: &[Message]) -> Result<Conversation> {
+    let messages = match messages.split_first() {
+        Some((first, rest)) if first.role == "system" => {
+            let mut config = generation.config().clone();
+            config.system_prompt = first.content.clone();
+            generation.set_config(config)?;
+            rest
+        }
+        _ => messages,
+    };
+    let mut conversation = generation.new_conversation();
+    for message in messages {
+        conversation.add_message(message.clone());
+    }
+    Ok(conversation)
+}
+
+/// Prints the answer to stdout, with reasoning dimmed
+#[derive(Default)]
+struct ChatSink {
+    reasoning: bool,
+}
+
+impl ChatSink {
+    fn print(&mut self, text: &str, reasoning: bool) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        if reasoning != self.reasoning {
+            stdout.write_all(if reasoning { b"\x1b[2m" } else { b"\x1b[0m\n" })?;
+            self.reasoning = reasoning;
+        }
+        stdout.write_all(text.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+impl TokenSink for ChatSink {
+    fn on_token(&mut self, text: &str) -> Result<()> {
+        self.print(text, false)
+    }
+
+    fn on_reasoning(&mut self, text: &str) -> Result<()> {
+        self.print(text, true)
+    }
+}
+
+impl Drop for ChatSink {
+    fn drop(&mut self) {
+        if self.reasoning {
+            print!("\x1b[0m");
+        }
+    }
+}
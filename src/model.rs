@@ -0,0 +1,386 @@
+//! Model backends
+//!
+//! `TextGeneration` only talks to a [`CausalLM`], so anything producing
+//! next-token logits can drive it, including a scripted stand-in for tests.
+//! [`Model`] implements it for the supported architectures, either quantized
+//! from a GGUF file or at full precision from a HuggingFace model directory
+//! (config.json and safetensors). Each has its own candle implementation with
+//! its own loader and output shape, picked from the `general.architecture`
+//! metadata key or the `model_type` of config.json.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use candle_transformers::models::mimi::candle::quantized::gguf_file;
+use candle_transformers::models::mimi::candle::{DType, Device, Tensor};
+use candle_transformers::models::mimi::candle_nn::VarBuilder;
+use candle_transformers::models::smol::quantized_smollm3::QuantizedModelForCausalLM;
+use candle_transformers::models::smol::smollm3;
+use candle_transformers::models::{
+    gemma3, llama, phi3, quantized_gemma3, quantized_llama, quantized_phi3, quantized_qwen2,
+    quantized_qwen3, qwen2, qwen3,
+};
+
+/// A causal language model as seen by the generation loop
+pub trait CausalLM: Send {
+    /// Run `input` token ids of shape (batch, seq_len), placed at position `index_pos`
+    /// after the tokens already in the KV cache; returns the logits of the last
+    /// position with shape (batch, vocab)
+    fn forward(
+        &mut self,
+        input: &Tensor,
+        index_pos: usize,
+    ) -> candle_transformers::models::mimi::candle::Result<Tensor>;
+
+    /// Forget every cached position, so the next `forward` starts at 0
+    fn clear_kv_cache(&mut self);
+
+    fn vocab_size(&self) -> usize;
+
+    /// Maximum number of positions, prompt and generated tokens together
+    fn context_length(&self) -> usize;
+
+    /// Whether `forward` accepts several tokens at a nonzero `index_pos`, which prompt
+    /// prefix reuse relies on; without it the cache is cleared and the prompt prefilled
+    /// from position 0
+    fn supports_offset_prefill(&self) -> bool {
+        true
+    }
+
+    /// Architecture of a [`Model`], `None` for other backends
+    fn architecture(&self) -> Option<Architecture> {
+        None
+    }
+}
+
+/// Model families that can be loaded from GGUF or safetensors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    SmolLm3,
+    /// Llama and the architectures sharing its layout, e.g. Mistral
+    Llama,
+    Qwen2,
+    Qwen3,
+    Phi3,
+    Gemma3,
+}
+
+impl Architecture {
+    /// Read `general.architecture` from the GGUF header
+    pub fn from_gguf(content: &gguf_file::Content) -> Result<Self> {
+        Self::from_name(architecture_name(content)?)
+    }
+
+    /// Read `model_type` from a HuggingFace config.json
+    pub fn from_config(config: &serde_json::Value) -> Result<Self> {
+        match config["model_type"].as_str() {
+            // Mistral checkpoints load with the Llama implementation; in GGUF they are `llama`
+            Some("mistral") => Ok(Self::Llama),
+            Some(name) => Self::from_name(name),
+            None => bail!("config.json has no model_type"),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        let architecture = match name {
+            "smollm3" => Self::SmolLm3,
+            "llama" => Self::Llama,
+            "qwen2" => Self::Qwen2,
+            "qwen3" => Self::Qwen3,
+            "phi3" => Self::Phi3,
+            "gemma3" | "gemma3_text" => Self::Gemma3,
+            _ => bail!(
+                "unsupported architecture {name:?}, expected one of \
+                 smollm3, llama, qwen2, qwen3, phi3 or gemma3"
+            ),
+        };
+        Ok(architecture)
+    }
+}
+
+/// A loaded model of any supported architecture, quantized or full precision
+pub struct Model {
+    weights: Weights,
+    architecture: Architecture,
+    vocab_size: usize,
+    context_length: usize,
+}
+
+enum Weights {
+    SmolLm3(QuantizedModelForCausalLM),
+    Llama(quantized_llama::ModelWeights),
+    Qwen2(quantized_qwen2::ModelWeights),
+    Qwen3(quantized_qwen3::ModelWeights),
+    Phi3(quantized_phi3::ModelWeights),
+    Gemma3(quantized_gemma3::ModelWeights),
+    FullSmolLm3(smollm3::ModelForCausalLM),
+    FullLlama {
+        model: llama::Llama,
+        cache: llama::Cache,
+        /// Fresh cache to restore on `clear_kv_cache`, the rotary tables are kept
+        empty_cache: llama::Cache,
+    },
+    FullQwen2(qwen2::ModelForCausalLM),
+    FullQwen3(qwen3::ModelForCausalLM),
+    FullPhi3(phi3::Model),
+    FullGemma3(gemma3::Model),
+}
+
+impl Model {
+    /// Load a GGUF file whose header `content` was already read, detecting its
+    /// architecture from it
+    pub fn from_gguf(
+        path: impl AsRef<Path>,
+        content: &gguf_file::Content,
+        device: &Device,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let architecture = Architecture::from_gguf(content)?;
+        let prefix = architecture_name(content)?.to_string();
+        let metadata_usize = |key: &str| -> Option<usize> {
+            let value = content.metadata.get(&format!("{prefix}.{key}"))?;
+            value
+                .to_u64()
+                .ok()
+                .or_else(|| value.to_u32().ok().map(u64::from))?
+                .try_into()
+                .ok()
+        };
+        let context_length = metadata_usize("context_length")
+            .ok_or_else(|| anyhow::anyhow!("GGUF file has no {prefix}.context_length"))?;
+        let vocab_size = match metadata_usize("vocab_size") {
+            Some(vocab_size) => vocab_size,
+            None => match content.metadata.get("tokenizer.ggml.tokens") {
+                Some(tokens) => tokens.to_vec()?.len(),
+                None => bail!("GGUF file has no vocabulary size"),
+            },
+        };
+
+        let weights = match architecture {
+            // SmolLM3's loader reads the file itself
+            Architecture::SmolLm3 => {
+                Weights::SmolLm3(QuantizedModelForCausalLM::from_gguf(path, device)?)
+            }
+            Architecture::Llama => Weights::Llama(quantized_llama::ModelWeights::from_gguf(
+                copy_content(content),
+                &mut file,
+                device,
+            )?),
+            Architecture::Qwen2 => Weights::Qwen2(quantized_qwen2::ModelWeights::from_gguf(
+                copy_content(content),
+                &mut file,
+                device,
+            )?),
+            Architecture::Qwen3 => Weights::Qwen3(quantized_qwen3::ModelWeights::from_gguf(
+                copy_content(content),
+                &mut file,
+                device,
+            )?),
+            Architecture::Phi3 => Weights::Phi3(quantized_phi3::ModelWeights::from_gguf(
+                false,
+                copy_content(content),
+                &mut file,
+                device,
+            )?),
+            Architecture::Gemma3 => Weights::Gemma3(quantized_gemma3::ModelWeights::from_gguf(
+                copy_content(content),
+                &mut file,
+                device,
+            )?),
+        };
+        Ok(Self {
+            weights,
+            architecture,
+            vocab_size,
+            context_length,
+        })
+    }
+
+    /// Load a HuggingFace model directory: config.json plus one or more safetensors
+    /// files, with the weights converted to `dtype`
+    pub fn from_safetensors(dir: impl AsRef<Path>, dtype: DType, device: &Device) -> Result<Self> {
+        let dir = dir.as_ref();
+        let config_json = std::fs::read_to_string(dir.join("config.json"))
+            .with_context(|| format!("reading {}", dir.join("config.json").display()))?;
+        let config: serde_json::Value = serde_json::from_str(&config_json)?;
+        let architecture = Architecture::from_config(&config)?;
+        let config_usize = |key: &str| -> Result<usize> {
+            match config[key].as_u64() {
+                Some(value) => Ok(value.try_into()?),
+                None => bail!("config.json has no {key}"),
+            }
+        };
+        let context_length = config_usize("max_position_embeddings")?;
+        let vocab_size = config_usize("vocab_size")?;
+
+        let files = safetensors_files(dir)?;
+        // SAFETY: the files are memory-mapped and must not be modified while the model is loaded
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files, dtype, device)? };
+        let weights = match architecture {
+            Architecture::SmolLm3 => Weights::FullSmolLm3(smollm3::ModelForCausalLM::new(
+                &serde_json::from_str(&config_json)?,
+                vb,
+            )?),
+            Architecture::Llama => {
+                let config =
+                    serde_json::from_str::<llama::LlamaConfig>(&config_json)?.into_config(false);
+                let cache = llama::Cache::new(true, dtype, &config, device)?;
+                Weights::FullLlama {
+                    model: llama::Llama::load(vb, &config)?,
+                    empty_cache: cache.clone(),
+                    cache,
+                }
+            }
+            Architecture::Qwen2 => Weights::FullQwen2(qwen2::ModelForCausalLM::new(
+                &serde_json::from_str(&config_json)?,
+                vb,
+            )?),
+            Architecture::Qwen3 => Weights::FullQwen3(qwen3::ModelForCausalLM::new(
+                &serde_json::from_str(&config_json)?,
+                vb,
+            )?),
+            Architecture::Phi3 => {
+                Weights::FullPhi3(phi3::Model::new(&serde_json::from_str(&config_json)?, vb)?)
+            }
+            Architecture::Gemma3 => Weights::FullGemma3(gemma3::Model::new(
+                false,
+                &serde_json::from_str(&config_json)?,
+                vb,
+            )?),
+        };
+        Ok(Self {
+            weights,
+            architecture,
+            vocab_size,
+            context_length,
+        })
+    }
+}
+
+impl CausalLM for Model {
+    fn forward(
+        &mut self,
+        input: &Tensor,
+        index_pos: usize,
+    ) -> candle_transformers::models::mimi::candle::Result<Tensor> {
+        match &mut self.weights {
+            Weights::SmolLm3(model) => model.forward(input, index_pos)?.squeeze(1),
+            Weights::Llama(model) => model.forward(input, index_pos),
+            Weights::Qwen2(model) => model.forward(input, index_pos),
+            Weights::Qwen3(model) => model.forward(input, index_pos),
+            Weights::Phi3(model) => model.forward(input, index_pos),
+            Weights::Gemma3(model) => model.forward(input, index_pos),
+            Weights::FullSmolLm3(model) => model.forward(input, index_pos)?.squeeze(1),
+            Weights::FullLlama { model, cache, .. } => model.forward(input, index_pos, cache),
+            Weights::FullQwen2(model) => model.forward(input, index_pos)?.squeeze(1),
+            Weights::FullQwen3(model) => model.forward(input, index_pos)?.squeeze(1),
+            Weights::FullPhi3(model) => model.forward(input, index_pos)?.squeeze(1),
+            Weights::FullGemma3(model) => model.forward(input, index_pos)?.squeeze(1),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match &mut self.weights {
+            Weights::SmolLm3(model) => model.clear_kv_cache(),
+            Weights::Qwen3(model) => model.clear_kv_cache(),
+            Weights::FullSmolLm3(model) => model.clear_kv_cache(),
+            Weights::FullLlama {
+                cache, empty_cache, ..
+            } => *cache = empty_cache.clone(),
+            Weights::FullQwen2(model) => model.clear_kv_cache(),
+            Weights::FullQwen3(model) => model.clear_kv_cache(),
+            Weights::FullPhi3(model) => model.clear_kv_cache(),
+            Weights::FullGemma3(model) => model.clear_kv_cache(),
+            // These drop their cache themselves when a forward pass starts at position 0
+            Weights::Llama(_) | Weights::Qwen2(_) | Weights::Phi3(_) | Weights::Gemma3(_) => {}
+        }
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn supports_offset_prefill(&self) -> bool {
+        // The attention masks of these ignore or hide the cached positions when several
+        // tokens follow them
+        !matches!(
+            self.weights,
+            Weights::Llama(_)
+                | Weights::Qwen2(_)
+                | Weights::Phi3(_)
+                | Weights::Gemma3(_)
+                | Weights::FullLlama { .. }
+        )
+    }
+
+    fn architecture(&self) -> Option<Architecture> {
+        Some(self.architecture)
+    }
+}
+
+fn architecture_name(content: &gguf_file::Content) -> Result<&str> {
+    match content.metadata.get("general.architecture") {
+        Some(value) => Ok(value.to_string()?.as_str()),
+        None => bail!("GGUF file has no general.architecture"),
+    }
+}
+
+/// Copy of a GGUF header for the candle loaders, which take it by value, so the
+/// caller keeps its own for the tokenizer and chat template
+fn copy_content(content: &gguf_file::Content) -> gguf_file::Content {
+    gguf_file::Content {
+        magic: content.magic,
+        metadata: content.metadata.clone(),
+        tensor_infos: content
+            .tensor_infos
+            .iter()
+            .map(|(name, info)| {
+                let info = gguf_file::TensorInfo {
+                    ggml_dtype: info.ggml_dtype,
+                    shape: info.shape.clone(),
+                    offset: info.offset,
+                };
+                (name.clone(), info)
+            })
+            .collect(),
+        tensor_data_offset: content.tensor_data_offset,
+    }
+}
+
+/// The shards listed in model.safetensors.index.json, or every safetensors file in `dir`
+fn safetensors_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let index_path = dir.join("model.safetensors.index.json");
+    let mut files: Vec<PathBuf> = if index_path.exists() {
+        let index: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&index_path)?)?;
+        let Some(weight_map) = index["weight_map"].as_object() else {
+            bail!("{} has no weight_map", index_path.display());
+        };
+        weight_map
+            .values()
+            .filter_map(|file| file.as_str())
+            .map(|file| dir.join(file))
+            .collect()
+    } else {
+        std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.path()))
+            .filter(|path| {
+                path.as_ref().map_or(true, |path| {
+                    path.extension().is_some_and(|ext| ext == "safetensors")
+                })
+            })
+            .collect::<Result<_>>()?
+    };
+    files.sort();
+    files.dedup();
+    if files.is_empty() {
+        bail!("no safetensors files in {}", dir.display());
+    }
+    Ok(files)
+}
@@ -0,0 +1,214 @@
+//! Sampling strategies for picking the next token
+//!
+//! Greedy, temperature, top-k and top-p map directly onto candle's
+//! `Sampling`; min-p and typical-p filter the probabilities before a
+//! multinomial draw.
+
+use std::hash::{BuildHasher, RandomState};
+
+use anyhow::Result;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_transformers::models::mimi::candle::Tensor;
+use serde::{Deserialize, Serialize};
+
+/// Temperatures below this are treated as greedy decoding
+const MIN_TEMPERATURE: f64 = 1e-7;
+
+/// How the next token is chosen from the logits
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum SamplingConfig {
+    /// Always pick the most likely token
+    Greedy,
+    /// Sample from the whole distribution
+    Temperature { temperature: f64 },
+    /// Sample from the `k` most likely tokens
+    TopK { k: usize, temperature: f64 },
+    /// Nucleus sampling: smallest set of tokens whose probability exceeds `p`
+    TopP { p: f64, temperature: f64 },
+    /// Top-k followed by top-p
+    TopKTopP { k: usize, p: f64, temperature: f64 },
+    /// Drop tokens less likely than `p` times the most likely one
+    MinP { p: f64, temperature: f64 },
+    /// Locally typical sampling: keep tokens closest to the expected surprisal up to mass `p`
+    TypicalP { p: f64, temperature: f64 },
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self::TopP {
+            p: 0.5,
+            temperature: 0.6,
+        }
+    }
+}
+
+impl SamplingConfig {
+    pub fn temperature(&self) -> f64 {
+        match *self {
+            Self::Greedy => 0.0,
+            Self::Temperature { temperature }
+            | Self::TopK { temperature, .. }
+            | Self::TopP { temperature, .. }
+            | Self::TopKTopP { temperature, .. }
+            | Self::MinP { temperature, .. }
+            | Self::TypicalP { temperature, .. } => temperature,
+        }
+    }
+
+    /// Same strategy with another temperature; greedy becomes plain temperature sampling
+    pub fn with_temperature(self, temperature: f64) -> Self {
+        match self {
+            Self::Greedy | Self::Temperature { .. } => Self::Temperature { temperature },
+            Self::TopK { k, .. } => Self::TopK { k, temperature },
+            Self::TopP { p, .. } => Self::TopP { p, temperature },
+            Self::TopKTopP { k, p, .. } => Self::TopKTopP { k, p, temperature },
+            Self::MinP { p, .. } => Self::MinP { p, temperature },
+            Self::TypicalP { p, .. } => Self::TypicalP { p, temperature },
+        }
+    }
+
+    fn to_candle(&self) -> Sampling {
+        let temperature = self.temperature();
+        if temperature < MIN_TEMPERATURE {
+            return Sampling::ArgMax;
+        }
+        match *self {
+            Self::Greedy => Sampling::ArgMax,
+            Self::TopK { k, .. } => Sampling::TopK { k, temperature },
+            Self::TopP { p, .. } => Sampling::TopP { p, temperature },
+            Self::TopKTopP { k, p, .. } => Sampling::TopKThenTopP { k, p, temperature },
+            Self::Temperature { .. } | Self::MinP { .. } | Self::TypicalP { .. } => {
+                Sampling::All { temperature }
+            }
+        }
+    }
+}
+
+/// Picks tokens according to a `SamplingConfig`
+pub struct Sampler {
+    logits_processor: LogitsProcessor,
+    config: SamplingConfig,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, config: SamplingConfig) -> Self {
+        Self {
+            logits_processor: LogitsProcessor::from_sampling(seed, config.to_candle()),
+            config,
+        }
+    }
+
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        let token = match self.config {
+            SamplingConfig::MinP { p, .. } => self
+                .logits_processor
+                .sample_f(logits, |prs| min_p_filter(prs, p as f32))?,
+            SamplingConfig::TypicalP { p, .. } => self
+                .logits_processor
+                .sample_f(logits, |prs| typical_p_filter(prs, p as f32))?,
+            _ => self.logits_processor.sample(logits)?,
+        };
+        Ok(token)
+    }
+}
+
+/// Fresh seed for runs that did not ask for a fixed one
+pub fn random_seed() -> u64 {
+    RandomState::new().hash_one(std::time::SystemTime::now())
+}
+
+fn min_p_filter(prs: &mut [f32], min_p: f32) {
+    let max = prs.iter().copied().fold(0f32, f32::max);
+    let threshold = max * min_p;
+    for pr in prs.iter_mut() {
+        if *pr < threshold {
+            *pr = 0.0;
+        }
+    }
+}
+
+fn typical_p_filter(prs: &mut [f32], typical_p: f32) {
+    if typical_p <= 0.0 || typical_p >= 1.0 {
+        return;
+    }
+    let entropy: f32 = prs
+        .iter()
+        .filter(|&&pr| pr > 0.0)
+        .map(|&pr| -pr * pr.ln())
+        .sum();
+
+    // Sort by how far each token's surprisal is from the entropy
+    let mut indices: Vec<usize> = (0..prs.len()).filter(|&i| prs[i] > 0.0).collect();
+    indices.sort_by(|&i, &j| {
+        let di = (-prs[i].ln() - entropy).abs();
+        let dj = (-prs[j].ln() - entropy).abs();
+        di.total_cmp(&dj)
+    });
+
+    let mut cumsum = 0.0;
+    for index in indices {
+        if cumsum >= typical_p {
+            prs[index] = 0.0;
+        } else {
+            cumsum += prs[index];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_transformers::models::mimi::candle::Device;
+
+    #[test]
+    fn min_p_drops_tokens_below_a_fraction_of_the_max() {
+        let mut prs = [0.5, 0.2, 0.05, 0.25];
+        min_p_filter(&mut prs, 0.3);
+        assert_eq!(prs, [0.5, 0.2, 0.0, 0.25]);
+
+        let mut prs = [0.2, 0.5, 0.3];
+        min_p_filter(&mut prs, 1.0);
+        assert_eq!(prs, [0.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn typical_p_keeps_tokens_closest_to_the_entropy() {
+        // Entropy is 1.75 bits: the 2-bit token is the most typical, then the 1-bit one
+        let mut prs = [0.5, 0.25, 0.125, 0.125];
+        typical_p_filter(&mut prs, 0.5);
+        assert_eq!(prs, [0.5, 0.25, 0.0, 0.0]);
+
+        let mut prs = [0.5, 0.25, 0.125, 0.125];
+        typical_p_filter(&mut prs, 0.2);
+        assert_eq!(prs, [0.0, 0.25, 0.0, 0.0]);
+
+        let mut prs = [0.5, 0.25, 0.125, 0.125];
+        typical_p_filter(&mut prs, 1.0);
+        assert_eq!(prs, [0.5, 0.25, 0.125, 0.125]);
+    }
+
+    #[test]
+    fn greedy_and_near_zero_temperature_pick_the_argmax() {
+        assert_eq!(SamplingConfig::Greedy.to_candle(), Sampling::ArgMax);
+        let cold = SamplingConfig::TopP {
+            p: 0.9,
+            temperature: MIN_TEMPERATURE / 2.0,
+        };
+        assert_eq!(cold.to_candle(), Sampling::ArgMax);
+        assert_eq!(
+            SamplingConfig::MinP {
+                p: 0.1,
+                temperature: 0.7
+            }
+            .to_candle(),
+            Sampling::All { temperature: 0.7 }
+        );
+
+        let logits = Tensor::new(&[0.1f32, 2.0, 0.5], &Device::Cpu).unwrap();
+        for config in [SamplingConfig::Greedy, cold] {
+            let mut sampler = Sampler::new(0, config);
+            assert_eq!(sampler.sample(&logits).unwrap(), 1);
+        }
+    }
+}
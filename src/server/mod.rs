@@ -0,0 +1,522 @@
+//! HTTP API over `TextGeneration`
+//!
+//! Speaks the OpenAI chat-completions and completions formats, so existing
+//! OpenAI SDK clients can talk to a local model without code changes, and
+//! Ollama's `/api/*` endpoints for tools that only know Ollama.
+
+mod ollama;
+mod openai;
+
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use axum::Json;
+use axum::Router;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use candle_core::quantized::gguf_file;
+use chrono::{DateTime, Utc};
+
+use crate::async_generation::{GenerationEventStream, spawn_stream};
+use crate::chat_template::Message;
+use crate::generation::{GenerationOutput, PromptTooLong, TextGeneration};
+use crate::generation_config::{GenerationConfig, GenerationConfigError, GenerationRequest};
+use crate::sampling::{SamplingConfig, random_seed};
+use crate::sink::NullSink;
+
+/// State shared by all request handlers
+#[derive(Clone)]
+pub struct AppState {
+    generation: Arc<Mutex<TextGeneration>>,
+    model_name: String,
+    model_info: Arc<ModelInfo>,
+}
+
+/// Description of the served model for the listing endpoints
+#[derive(Debug, Default)]
+struct ModelInfo {
+    /// `gguf` or `safetensors`
+    format: &'static str,
+    size: u64,
+    modified_at: Option<DateTime<Utc>>,
+    /// Scalar GGUF metadata, e.g. `general.architecture`; for safetensors the
+    /// scalar config.json entries, with `model_type` as `general.architecture`
+    metadata: serde_json::Map<String, serde_json::Value>,
+    chat_template: Option<String>,
+}
+
+/// Generation parameters a client can set per request
+#[derive(Debug, Default)]
+pub struct RequestParams {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub max_tokens: Option<usize>,
+    /// `None` keeps the configured stop sequences
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+    pub think: Option<bool>,
+}
+
+impl AppState {
+    pub fn new(generation: TextGeneration, model_name: impl Into<String>) -> Self {
+        Self {
+            generation: Arc::new(Mutex::new(generation)),
+            model_name: model_name.into(),
+            model_info: Arc::default(),
+        }
+    }
+
+    /// Describe the served model from its GGUF file (size, date and the header `gguf`
+    /// returned by `setup_with_header`) or its safetensors directory
+    pub fn with_model_file(
+        mut self,
+        path: impl AsRef<Path>,
+        gguf: Option<&gguf_file::Content>,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let Some(content) = gguf else {
+            return self.with_model_dir(path);
+        };
+        let file_metadata = std::fs::metadata(path)?;
+
+        let metadata = content
+            .metadata
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), gguf_value_to_json(value)?)))
+            .collect();
+        let chat_template = content
+            .metadata
+            .get("tokenizer.chat_template")
+            .and_then(|value| value.to_string().ok())
+            .cloned();
+        self.model_info = Arc::new(ModelInfo {
+            format: "gguf",
+            size: file_metadata.len(),
+            modified_at: file_metadata.modified().ok().map(DateTime::from),
+            metadata,
+            chat_template,
+        });
+        Ok(self)
+    }
+
+    fn with_model_dir(mut self, dir: &Path) -> anyhow::Result<Self> {
+        let config_path = dir.join("config.json");
+        let config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&config_path)?)?;
+        let mut metadata: serde_json::Map<_, _> = config
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(_, value)| !value.is_object() && !value.is_array())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        if let Some(model_type) = metadata.get("model_type").cloned() {
+            metadata.insert("general.architecture".to_string(), model_type);
+        }
+
+        let mut size = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "safetensors") {
+                size += std::fs::metadata(path)?.len();
+            }
+        }
+        self.model_info = Arc::new(ModelInfo {
+            format: "safetensors",
+            size,
+            modified_at: std::fs::metadata(&config_path)?
+                .modified()
+                .ok()
+                .map(DateTime::from),
+            metadata,
+            chat_template: None,
+        });
+        Ok(self)
+    }
+
+    /// Run a generation to completion on a blocking thread
+    async fn generate(
+        &self,
+        prompt: Prompt,
+        params: RequestParams,
+    ) -> Result<GenerationOutput, ApiError> {
+        let generation = self.generation.clone();
+        let output = tokio::task::spawn_blocking(move || {
+            let mut generation = generation.lock().unwrap_or_else(PoisonError::into_inner);
+            let request = params.into_request(generation.config());
+            let prompt = prompt.render(&generation, &request)?;
+            generation
+                .stream_raw(&prompt, &request)?
+                .into_output(&mut NullSink)
+        })
+        .await
+        .map_err(|err| ApiError::internal(err.to_string()))??;
+        Ok(output)
+    }
+
+    /// Stream a generation from a worker thread
+    fn generate_stream(&self, prompt: Prompt, params: RequestParams) -> GenerationEventStream {
+        spawn_stream(self.generation.clone(), move |generation| {
+            let request = params.into_request(generation.config());
+            let prompt = prompt.render(generation, &request)?;
+            generation.stream_raw(&prompt, &request)
+        })
+    }
+}
+
+/// Input of a request
+pub enum Prompt {
+    /// Chat history, rendered with the model's chat template
+    Messages(Vec<Message>),
+    /// Text fed to the model as is
+    Raw(String),
+}
+
+impl Prompt {
+    fn render(
+        self,
+        generation: &TextGeneration,
+        request: &GenerationRequest,
+    ) -> anyhow::Result<String> {
+        match self {
+            Prompt::Messages(messages) => generation.format_messages(&messages, request),
+            Prompt::Raw(prompt) => Ok(prompt),
+        }
+    }
+}
+
+impl RequestParams {
+    /// Overrides for one request, like `Config::override_sampling`: `top_p` and `top_k`
+    /// replace the model's strategy, `temperature` alone keeps it
+    fn into_request(self, defaults: &GenerationConfig) -> GenerationRequest {
+        let temperature = self
+            .temperature
+            .unwrap_or_else(|| defaults.sampling.temperature());
+        let sampling = match (self.top_k, self.top_p) {
+            (None, None) => self
+                .temperature
+                .map(|temperature| defaults.sampling.clone().with_temperature(temperature)),
+            (Some(k), Some(p)) => Some(SamplingConfig::TopKTopP { k, p, temperature }),
+            (Some(k), None) => Some(SamplingConfig::TopK { k, temperature }),
+            (None, Some(p)) => Some(SamplingConfig::TopP { p, temperature }),
+        };
+        GenerationRequest {
+            sampling,
+            seed: self.seed,
+            max_tokens: self.max_tokens,
+            stop_sequences: self.stop,
+            enable_thinking: self.think,
+            ..Default::default()
+        }
+    }
+}
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/v1/chat/completions", post(openai::chat_completions))
+        .route("/v1/completions", post(openai::completions))
+        .route("/v1/models", get(openai::models))
+        .route("/api/generate", post(ollama::generate))
+        .route("/api/chat", post(ollama::chat))
+        .route("/api/tags", get(ollama::tags))
+        .route("/api/show", post(ollama::show))
+        .with_state(state)
+}
+
+pub async fn serve(addr: &str, state: AppState) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
+
+/// Unique id for a response, e.g. `chatcmpl-3f2a...`
+fn response_id(prefix: &str) -> String {
+    format!("{prefix}-{:016x}", random_seed())
+}
+
+fn unix_timestamp() -> i64 {
+    Utc::now().timestamp()
+}
+
+fn gguf_value_to_json(value: &gguf_file::Value) -> Option<serde_json::Value> {
+    use gguf_file::Value;
+    let value = match value {
+        Value::U8(v) => (*v).into(),
+        Value::I8(v) => (*v).into(),
+        Value::U16(v) => (*v).into(),
+        Value::I16(v) => (*v).into(),
+        Value::U32(v) => (*v).into(),
+        Value::I32(v) => (*v).into(),
+        Value::U64(v) => (*v).into(),
+        Value::I64(v) => (*v).into(),
+        Value::F32(v) => (*v).into(),
+        Value::F64(v) => (*v).into(),
+        Value::Bool(v) => (*v).into(),
+        Value::String(v) => v.clone().into(),
+        // Vocabularies and merges are far too large to be useful here
+        Value::Array(_) => return None,
+    };
+    Some(value)
+}
+
+/// Error sent back to the client in OpenAI's error format
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn internal(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: message.into(),
+        }
+    }
+
+    /// OpenAI's error `type` for this status
+    fn kind(&self) -> &'static str {
+        if self.status.is_client_error() {
+            "invalid_request_error"
+        } else {
+            "server_error"
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        // Out-of-range parameters and oversized prompts are the client's mistake
+        let status = if err.is::<GenerationConfigError>() || err.is::<PromptTooLong>() {
+            StatusCode::BAD_REQUEST
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        Self {
+            status,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "error": {
+                "message": self.message,
+                "type": self.kind(),
+            }
+        });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scripted_generation;
+    use axum::extract::State;
+    use serde_json::json;
+
+    const PIECES: &[&str] = &["a", "b", "ok", " ", "```", "\n", " more", "<|im_end|>"];
+
+    /// Server over a scripted model whose configured stop sequence is "```"
+    fn state(script: &[&str]) -> AppState {
+        let (mut generation, _) = scripted_generation(PIECES, script);
+        let mut config = generation.config().clone();
+        config.stop_sequences = vec!["```".to_string()];
+        generation.set_config(config).unwrap();
+        AppState::new(generation, "test-model")
+    }
+
+    async fn body_json(response: Response) -> (StatusCode, serde_json::Value) {
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    async fn complete(state: AppState, request: serde_json::Value) -> Response {
+        let request = serde_json::from_value(request).unwrap();
+        match openai::completions(State(state), Json(request)).await {
+            Ok(response) => response,
+            Err(err) => err.into_response(),
+        }
+    }
+
+    #[test]
+    fn temperature_alone_keeps_the_strategy() {
+        let defaults = GenerationConfig {
+            sampling: SamplingConfig::MinP {
+                p: 0.1,
+                temperature: 0.7,
+            },
+            ..Default::default()
+        };
+        let params = RequestParams {
+            temperature: Some(0.2),
+            ..Default::default()
+        };
+        let request = params.into_request(&defaults);
+        assert_eq!(
+            request.sampling,
+            Some(SamplingConfig::MinP {
+                p: 0.1,
+                temperature: 0.2
+            })
+        );
+        assert_eq!(request.stop_sequences, None);
+    }
+
+    #[test]
+    fn top_p_replaces_the_strategy() {
+        let params = RequestParams {
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        let request = params.into_request(&GenerationConfig::default());
+        let temperature = GenerationConfig::default().sampling.temperature();
+        assert_eq!(
+            request.sampling,
+            Some(SamplingConfig::TopP {
+                p: 0.9,
+                temperature
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn completion_uses_configured_stop_sequences() {
+        let response = complete(
+            state(&["ok", " ", "```", "\n", " more"]),
+            json!({"prompt": "a b"}),
+        )
+        .await;
+        let (status, body) = body_json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["choices"][0]["text"], "ok ");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        assert_eq!(body["usage"]["prompt_tokens"], 2);
+        assert_eq!(body["usage"]["completion_tokens"], 3);
+    }
+
+    #[tokio::test]
+    async fn request_stop_replaces_configured_ones() {
+        let response = complete(
+            state(&["ok", " ", "```", " more", "<|im_end|>"]),
+            json!({"prompt": "a", "stop": "more"}),
+        )
+        .await;
+        let (_, body) = body_json(response).await;
+        assert_eq!(body["choices"][0]["text"], "ok ``` ");
+    }
+
+    #[tokio::test]
+    async fn chat_completion_returns_the_message() {
+        let request = json!({
+            "messages": [{"role": "user", "content": "a"}],
+            "max_tokens": 2,
+        });
+        let request = serde_json::from_value(request).unwrap();
+        let response = openai::chat_completions(State(state(&["ok", " more"])), Json(request))
+            .await
+            .unwrap();
+        let (status, body) = body_json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["model"], "test-model");
+        assert_eq!(body["choices"][0]["message"]["content"], "ok more");
+        assert_eq!(body["choices"][0]["finish_reason"], "length");
+    }
+
+    #[tokio::test]
+    async fn streamed_completion_ends_with_done() {
+        let response = complete(
+            state(&["ok", " more", "<|im_end|>"]),
+            json!({"prompt": "a", "stream": true}),
+        )
+        .await;
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        let data: Vec<_> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect();
+        assert_eq!(data.last(), Some(&"[DONE]"));
+        let text: String = data[..data.len() - 1]
+            .iter()
+            .map(|chunk| {
+                let chunk: serde_json::Value = serde_json::from_str(chunk).unwrap();
+                chunk["choices"][0]["text"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(text, "ok more");
+    }
+
+    async fn ollama_generate(state: AppState, request: serde_json::Value) -> Response {
+        let request = serde_json::from_value(request).unwrap();
+        match ollama::generate(State(state), Json(request)).await {
+            Ok(response) => response,
+            Err(err) => err.into_response(),
+        }
+    }
+
+    #[tokio::test]
+    async fn ollama_options_keep_configured_stop_sequences() {
+        let response = ollama_generate(
+            state(&["ok", " ", "```", " more"]),
+            json!({"prompt": "a", "raw": true, "stream": false, "options": {"num_predict": -1}}),
+        )
+        .await;
+        let (_, body) = body_json(response).await;
+        assert_eq!(body["response"], "ok ");
+        assert_eq!(body["done_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn ollama_stream_ends_with_prompt_statistics() {
+        let response = ollama_generate(
+            state(&["ok", " more", "<|im_end|>"]),
+            json!({"prompt": "a b", "raw": true}),
+        )
+        .await;
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let chunks: Vec<serde_json::Value> = String::from_utf8(bytes.to_vec())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let last = chunks.last().unwrap();
+        assert_eq!(last["done"], true);
+        assert_eq!(last["prompt_eval_count"], 2);
+        assert_eq!(last["eval_count"], 3);
+        assert!(last["prompt_eval_duration"].is_u64());
+        let total = last["total_duration"].as_u64().unwrap();
+        let eval = last["eval_duration"].as_u64().unwrap();
+        let prompt = last["prompt_eval_duration"].as_u64().unwrap();
+        assert_eq!(total, prompt + eval);
+    }
+
+    #[tokio::test]
+    async fn prompt_longer_than_the_context_is_a_client_error() {
+        let prompt = "a ".repeat(4096);
+        let response = complete(state(&["ok"]), json!({ "prompt": prompt })).await;
+        let (status, body) = body_json(response).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+
+    #[tokio::test]
+    async fn invalid_parameters_are_a_client_error() {
+        let response = complete(state(&["ok"]), json!({"prompt": "a", "top_p": 2.0})).await;
+        let (status, body) = body_json(response).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+}
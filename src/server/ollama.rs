@@ -0,0 +1,387 @@
+//! Ollama-compatible endpoints: `/api/generate`, `/api/chat`, `/api/tags` and `/api/show`
+//!
+//! Streaming responses are newline-delimited JSON, one object per chunk,
+//! ending with a `"done": true` object that carries the timing statistics.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Json;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use futures::{Stream, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+
+use super::{ApiError, AppState, Prompt, RequestParams};
+use crate::async_generation::GenerationEventStream;
+use crate::chat_template::Message;
+use crate::generation::{FinishReason, GenerationOutput};
+
+#[derive(Debug, Deserialize)]
+pub struct GenerateRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub prompt: String,
+    pub system: Option<String>,
+    /// Feed the prompt to the model without applying the chat template
+    #[serde(default)]
+    pub raw: bool,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub options: Options,
+    pub think: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<Message>,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub options: Options,
+    pub think: Option<bool>,
+}
+
+/// Subset of Ollama's model options that maps onto our generation parameters
+#[derive(Debug, Default, Deserialize)]
+pub struct Options {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    /// Maximum tokens to generate, negative to generate until EOS or the context is full
+    pub num_predict: Option<i64>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+}
+
+/// Ollama streams unless told otherwise
+fn default_stream() -> bool {
+    true
+}
+
+impl Options {
+    fn into_params(self, think: Option<bool>) -> RequestParams {
+        RequestParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            max_tokens: self
+                .num_predict
+                .map(|n| usize::try_from(n).unwrap_or(usize::MAX)),
+            stop: self.stop,
+            seed: self.seed,
+            think,
+        }
+    }
+}
+
+/// Timing statistics of the final `"done": true` object, durations in nanoseconds
+#[derive(Debug, Serialize)]
+struct Stats {
+    done_reason: &'static str,
+    total_duration: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_eval_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_eval_duration: Option<u64>,
+    eval_count: usize,
+    eval_duration: u64,
+}
+
+impl From<&GenerationOutput> for Stats {
+    fn from(output: &GenerationOutput) -> Self {
+        Self {
+            done_reason: done_reason(output.finish_reason),
+            total_duration: nanos(output.prompt_duration + output.generation_duration),
+            prompt_eval_count: Some(output.prompt_tokens),
+            prompt_eval_duration: Some(nanos(output.prompt_duration)),
+            eval_count: output.completion_tokens,
+            eval_duration: nanos(output.generation_duration),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GenerateChunk {
+    model: String,
+    created_at: String,
+    response: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+    done: bool,
+    #[serde(flatten)]
+    stats: Option<Stats>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatChunk {
+    model: String,
+    created_at: String,
+    message: ChatMessage,
+    done: bool,
+    #[serde(flatten)]
+    stats: Option<Stats>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatMessage {
+    role: &'static str,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+}
+
+fn done_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Length => "length",
+        FinishReason::Eos | FinishReason::Stop | FinishReason::Interrupted => "stop",
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+fn created_at() -> String {
+    Utc::now().to_rfc3339()
+}
+
+pub async fn generate(
+    State(state): State<AppState>,
+    Json(request): Json<GenerateRequest>,
+) -> Result<Response, OllamaError> {
+    let model = request.model.unwrap_or_else(|| state.model_name.clone());
+    // An empty prompt only asks Ollama to load the model
+    if request.prompt.is_empty() {
+        return Ok(Json(serde_json::json!({
+            "model": model,
+            "created_at": created_at(),
+            "response": "",
+            "done": true,
+            "done_reason": "load",
+        }))
+        .into_response());
+    }
+
+    let prompt = if request.raw {
+        Prompt::Raw(request.prompt)
+    } else {
+        let mut messages: Vec<_> = request.system.into_iter().map(Message::system).collect();
+        messages.push(Message::user(request.prompt));
+        Prompt::Messages(messages)
+    };
+    let params = request.options.into_params(request.think);
+
+    if request.stream {
+        let events = state.generate_stream(prompt, params);
+        let chunks = event_chunks(events, move |text, reasoning, stats| {
+            let (response, thinking) = split_text(text, reasoning);
+            GenerateChunk {
+                model: model.clone(),
+                created_at: created_at(),
+                response,
+                thinking,
+                done: stats.is_some(),
+                stats,
+            }
+        });
+        return Ok(ndjson(chunks));
+    }
+
+    let output = state.generate(prompt, params).await?;
+    Ok(Json(GenerateChunk {
+        model,
+        created_at: created_at(),
+        stats: Some(Stats::from(&output)),
+        response: output.text,
+        thinking: output.reasoning,
+        done: true,
+    })
+    .into_response())
+}
+
+pub async fn chat(
+    State(state): State<AppState>,
+    Json(request): Json<ChatRequest>,
+) -> Result<Response, OllamaError> {
+    let model = request.model.unwrap_or_else(|| state.model_name.clone());
+    let prompt = Prompt::Messages(request.messages);
+    let params = request.options.into_params(request.think);
+
+    if request.stream {
+        let events = state.generate_stream(prompt, params);
+        let chunks = event_chunks(events, move |text, reasoning, stats| {
+            let (content, thinking) = split_text(text, reasoning);
+            ChatChunk {
+                model: model.clone(),
+                created_at: created_at(),
+                message: ChatMessage {
+                    role: "assistant",
+                    content,
+                    thinking,
+                },
+                done: stats.is_some(),
+                stats,
+            }
+        });
+        return Ok(ndjson(chunks));
+    }
+
+    let output = state.generate(prompt, params).await?;
+    Ok(Json(ChatChunk {
+        model,
+        created_at: created_at(),
+        stats: Some(Stats::from(&output)),
+        message: ChatMessage {
+            role: "assistant",
+            content: output.text,
+            thinking: output.reasoning,
+        },
+        done: true,
+    })
+    .into_response())
+}
+
+pub async fn tags(State(state): State<AppState>) -> impl IntoResponse {
+    let info = &state.model_info;
+    Json(serde_json::json!({
+        "models": [{
+            "name": state.model_name,
+            "model": state.model_name,
+            "modified_at": info.modified_at.unwrap_or_else(Utc::now).to_rfc3339(),
+            "size": info.size,
+            "digest": "",
+            "details": details(&state),
+        }],
+    }))
+}
+
+/// Only one model is served, so the requested name is not checked
+pub async fn show(State(state): State<AppState>) -> impl IntoResponse {
+    let info = &state.model_info;
+    Json(serde_json::json!({
+        "modelfile": "",
+        "parameters": "",
+        "template": info.chat_template.clone().unwrap_or_default(),
+        "details": details(&state),
+        "model_info": info.metadata,
+    }))
+}
+
+fn details(state: &AppState) -> serde_json::Value {
+    let metadata = &state.model_info.metadata;
+    let family = metadata
+        .get("general.architecture")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    serde_json::json!({
+        "format": state.model_info.format,
+        "family": family,
+        "families": [family],
+        "parameter_size": metadata.get("general.size_label").and_then(|v| v.as_str()).unwrap_or_default(),
+        "quantization_level": "",
+    })
+}
+
+/// Put event text in the answer or the thinking field
+fn split_text(text: String, reasoning: bool) -> (String, Option<String>) {
+    if reasoning {
+        (String::new(), Some(text))
+    } else {
+        (text, None)
+    }
+}
+
+/// Turn generation events into response chunks: one per piece of text, plus a final
+/// chunk with statistics. `make` receives the text, whether it is reasoning, and the
+/// statistics for the final chunk.
+fn event_chunks<T, F>(
+    events: GenerationEventStream,
+    make: F,
+) -> impl Stream<Item = anyhow::Result<T>> + Send + 'static
+where
+    T: Send + 'static,
+    F: Fn(String, bool, Option<Stats>) -> T + Send + Sync + 'static,
+{
+    let make = Arc::new(make);
+    stream::unfold((events, 0usize), move |(mut events, mut eval_count)| {
+        let make = make.clone();
+        async move {
+            let chunks = match events.next().await? {
+                Err(err) => vec![Err(err)],
+                Ok(event) => {
+                    if event.token.is_some() {
+                        eval_count += 1;
+                    }
+                    let mut chunks = Vec::new();
+                    if !event.text.is_empty() {
+                        chunks.push(Ok(make(event.text, event.reasoning, None)));
+                    }
+                    if let Some(reason) = event.finish_reason {
+                        let run = events.stats();
+                        let prompt_duration =
+                            run.map(|run| run.prompt_duration).unwrap_or_default();
+                        let generation_duration =
+                            run.map(|run| run.generation_duration).unwrap_or_default();
+                        let stats = Stats {
+                            done_reason: done_reason(reason),
+                            total_duration: nanos(prompt_duration + generation_duration),
+                            prompt_eval_count: run.map(|run| run.prompt_tokens),
+                            prompt_eval_duration: run.map(|run| nanos(run.prompt_duration)),
+                            eval_count,
+                            eval_duration: nanos(generation_duration),
+                        };
+                        chunks.push(Ok(make(String::new(), false, Some(stats))));
+                    }
+                    chunks
+                }
+            };
+            Some((stream::iter(chunks), (events, eval_count)))
+        }
+    })
+    .flatten()
+}
+
+/// Newline-delimited JSON response; an error mid-stream is sent as `{"error": ...}`
+fn ndjson<T, S>(chunks: S) -> Response
+where
+    T: Serialize,
+    S: Stream<Item = anyhow::Result<T>> + Send + 'static,
+{
+    let lines = chunks.map(|chunk| {
+        let line = match chunk {
+            Ok(chunk) => serde_json::to_string(&chunk),
+            Err(err) => Ok(serde_json::json!({ "error": err.to_string() }).to_string()),
+        };
+        line.map(|line| line + "\n")
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Error in Ollama's format: `{"error": "message"}`
+#[derive(Debug)]
+pub struct OllamaError(ApiError);
+
+impl From<ApiError> for OllamaError {
+    fn from(err: ApiError) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for OllamaError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.0.message });
+        (self.0.status, Json(body)).into_response()
+    }
+}
@@ -0,0 +1,325 @@
+//! OpenAI-compatible endpoints: `/v1/chat/completions`, `/v1/completions` and `/v1/models`
+
+use std::convert::Infallible;
+
+use axum::Json;
+use axum::extract::State;
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use futures::{Stream, StreamExt, future, stream};
+use serde::{Deserialize, Serialize};
+
+use super::{ApiError, AppState, Prompt, RequestParams, response_id, unix_timestamp};
+use crate::chat_template::Message;
+use crate::generation::{FinishReason, GenerationEvent, GenerationOutput};
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<Message>,
+    #[serde(flatten)]
+    pub params: SamplingParams,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub prompt: String,
+    #[serde(flatten)]
+    pub params: SamplingParams,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// Sampling fields shared by both request kinds
+#[derive(Debug, Default, Deserialize)]
+pub struct SamplingParams {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    #[serde(alias = "max_completion_tokens")]
+    pub max_tokens: Option<usize>,
+    pub stop: Option<StopSequences>,
+    pub seed: Option<u64>,
+}
+
+/// `stop` may be a single string or a list of strings
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<SamplingParams> for RequestParams {
+    fn from(params: SamplingParams) -> Self {
+        Self {
+            temperature: params.temperature,
+            top_p: params.top_p,
+            top_k: None,
+            max_tokens: params.max_tokens,
+            stop: params.stop.map(|stop| match stop {
+                StopSequences::One(stop) => vec![stop],
+                StopSequences::Many(stop) => stop,
+            }),
+            seed: params.seed,
+            think: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+impl From<&GenerationOutput> for Usage {
+    fn from(output: &GenerationOutput) -> Self {
+        Self {
+            prompt_tokens: output.prompt_tokens,
+            completion_tokens: output.completion_tokens,
+            total_tokens: output.prompt_tokens + output.completion_tokens,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletion {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<ChatChoice>,
+    usage: Usage,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatChoice {
+    index: usize,
+    message: ChatMessage,
+    finish_reason: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatMessage {
+    role: &'static str,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletionChunk {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChunkChoice {
+    index: usize,
+    delta: Delta,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Completion {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<CompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionChoice {
+    index: usize,
+    text: String,
+    logprobs: Option<()>,
+    finish_reason: Option<&'static str>,
+}
+
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Length => "length",
+        FinishReason::Eos | FinishReason::Stop | FinishReason::Interrupted => "stop",
+    }
+}
+
+pub async fn chat_completions(
+    State(state): State<AppState>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, ApiError> {
+    let id = response_id("chatcmpl");
+    let created = unix_timestamp();
+    let model = request.model.unwrap_or_else(|| state.model_name.clone());
+    let prompt = Prompt::Messages(request.messages);
+
+    if request.stream {
+        let events = state.generate_stream(prompt, request.params.into());
+        let chunk = move |delta: Delta, finish_reason: Option<&'static str>| ChatCompletionChunk {
+            id: id.clone(),
+            object: "chat.completion.chunk",
+            created,
+            model: model.clone(),
+            choices: vec![ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+            }],
+        };
+        let role = chunk(
+            Delta {
+                role: Some("assistant"),
+                ..Default::default()
+            },
+            None,
+        );
+        let chunks = events.map(move |event| {
+            event.map(|event| {
+                let text = Some(event.text).filter(|text| !text.is_empty());
+                let delta = if event.reasoning {
+                    Delta {
+                        reasoning_content: text,
+                        ..Default::default()
+                    }
+                } else {
+                    Delta {
+                        content: text,
+                        ..Default::default()
+                    }
+                };
+                chunk(delta, event.finish_reason.map(finish_reason))
+            })
+        });
+        let chunks = stream::once(future::ready(Ok(role))).chain(chunks);
+        return Ok(sse(chunks).into_response());
+    }
+
+    let output = state.generate(prompt, request.params.into()).await?;
+    Ok(Json(ChatCompletion {
+        id,
+        object: "chat.completion",
+        created,
+        model,
+        usage: Usage::from(&output),
+        choices: vec![ChatChoice {
+            index: 0,
+            finish_reason: finish_reason(output.finish_reason),
+            message: ChatMessage {
+                role: "assistant",
+                content: output.text,
+                reasoning_content: output.reasoning,
+            },
+        }],
+    })
+    .into_response())
+}
+
+pub async fn completions(
+    State(state): State<AppState>,
+    Json(request): Json<CompletionRequest>,
+) -> Result<Response, ApiError> {
+    let id = response_id("cmpl");
+    let created = unix_timestamp();
+    let model = request.model.unwrap_or_else(|| state.model_name.clone());
+    let prompt = Prompt::Raw(request.prompt);
+
+    if request.stream {
+        let events = state.generate_stream(prompt, request.params.into());
+        let chunks = events.map(move |event| {
+            event.map(|event: GenerationEvent| Completion {
+                id: id.clone(),
+                object: "text_completion",
+                created,
+                model: model.clone(),
+                choices: vec![CompletionChoice {
+                    index: 0,
+                    text: event.text,
+                    logprobs: None,
+                    finish_reason: event.finish_reason.map(finish_reason),
+                }],
+                usage: None,
+            })
+        });
+        return Ok(sse(chunks).into_response());
+    }
+
+    let output = state.generate(prompt, request.params.into()).await?;
+    Ok(Json(Completion {
+        id,
+        object: "text_completion",
+        created,
+        model,
+        usage: Some(Usage::from(&output)),
+        choices: vec![CompletionChoice {
+            index: 0,
+            text: output.text,
+            logprobs: None,
+            finish_reason: Some(finish_reason(output.finish_reason)),
+        }],
+    })
+    .into_response())
+}
+
+pub async fn models(State(state): State<AppState>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "object": "list",
+        "data": [{
+            "id": state.model_name,
+            "object": "model",
+            "created": 0,
+            "owned_by": "llm-rs",
+        }],
+    }))
+}
+
+/// Server-Sent Events response: one `data:` line per chunk, then `data: [DONE]`.
+/// Errors during generation are sent as an OpenAI error object and end the stream.
+fn sse<T, S>(chunks: S) -> Sse<impl Stream<Item = Result<Event, Infallible>>>
+where
+    T: Serialize,
+    S: Stream<Item = anyhow::Result<T>> + Send + 'static,
+{
+    let events = chunks
+        .scan(false, |failed, chunk| {
+            if *failed {
+                return future::ready(None);
+            }
+            let data = match chunk {
+                Ok(chunk) => serde_json::to_string(&chunk).unwrap_or_default(),
+                Err(err) => {
+                    *failed = true;
+                    let err = ApiError::from(err);
+                    serde_json::json!({
+                        "error": { "message": err.message, "type": err.kind() }
+                    })
+                    .to_string()
+                }
+            };
+            future::ready(Some(Ok(Event::default().data(data))))
+        })
+        .chain(stream::once(future::ready(Ok(
+            Event::default().data("[DONE]")
+        ))));
+    Sse::new(events)
+}
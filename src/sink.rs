@@ -0,0 +1,65 @@
+//! Token sinks for streaming generated text
+//!
+//! `TextGeneration` hands every decoded chunk to a [`TokenSink`] as soon as
+//! `TokenOutputStream` produces it, so the same generation core can feed a
+//! terminal, a socket or a log file.
+
+use anyhow::Result;
+use std::io::Write;
+
+/// Receives decoded text chunks while a generation is running
+pub trait TokenSink {
+    fn on_token(&mut self, text: &str) -> Result<()>;
+
+    /// Text from inside a `<think>` block; dropped unless the sink overrides this
+    fn on_reasoning(&mut self, _text: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<F> TokenSink for F
+where
+    F: FnMut(&str) -> Result<()>,
+{
+    fn on_token(&mut self, text: &str) -> Result<()> {
+        self(text)
+    }
+}
+
+/// Sink that discards everything
+pub struct NullSink;
+
+impl TokenSink for NullSink {
+    fn on_token(&mut self, _text: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Sink that writes every chunk to a writer and flushes it immediately
+pub struct WriterSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> WriterSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl WriterSink<std::io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(std::io::stdout())
+    }
+}
+
+impl<W: Write> TokenSink for WriterSink<W> {
+    fn on_token(&mut self, text: &str) -> Result<()> {
+        self.writer.write_all(text.as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Helpers for unit tests
+
+use std::sync::{Arc, Mutex};
+
+use candle_transformers::models::mimi::candle::{Device, Tensor};
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::models::bpe::Vocab;
+use tokenizers::models::wordlevel::WordLevel;
+use tokenizers::pre_tokenizers::whitespace::WhitespaceSplit;
+use tokenizers::{AddedToken, Tokenizer};
+
+use crate::generation::TextGeneration;
+use crate::model::CausalLM;
+use crate::sampling::SamplingConfig;
+
+/// Tokenizer whose vocabulary is exactly `pieces`, in order, and which decodes by
+/// concatenation; text is encoded as one token per whitespace-separated word.
+/// Pieces of the form `<|...|>` are special tokens.
+pub fn word_tokenizer(pieces: &[&str]) -> Tokenizer {
+    let mut vocab = Vocab::default();
+    for piece in pieces {
+        let id = vocab.len() as u32;
+        vocab.entry(piece.to_string()).or_insert(id);
+    }
+    let unk = "<unk>".to_string();
+    let id = vocab.len() as u32;
+    vocab.entry(unk.clone()).or_insert(id);
+    let model = WordLevel::builder()
+        .vocab(vocab)
+        .unk_token(unk)
+        .build()
+        .unwrap();
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Some(WhitespaceSplit));
+    tokenizer.with_decoder(Some(Fuse::new()));
+    let special: Vec<_> = pieces
+        .iter()
+        .filter(|piece| piece.starts_with("<|") && piece.ends_with("|>"))
+        .map(|piece| AddedToken::from(piece.to_string(), true))
+        .collect();
+    tokenizer.add_special_tokens(&special);
+    tokenizer
+}
+
+/// `(index_pos, seq_len)` of each forward pass of a `ScriptedModel`
+pub type ForwardCalls = Arc<Mutex<Vec<(usize, usize)>>>;
+
+/// `CausalLM` whose n-th forward pass predicts `script[n]` (the last entry once the
+/// script runs out), recording the `(index_pos, seq_len)` of every call
+pub struct ScriptedModel {
+    script: Vec<u32>,
+    vocab_size: usize,
+    offset_prefill: bool,
+    calls: ForwardCalls,
+}
+
+impl ScriptedModel {
+    pub fn new(script: Vec<u32>, vocab_size: usize) -> Self {
+        Self {
+            script,
+            vocab_size,
+            offset_prefill: true,
+            calls: Arc::default(),
+        }
+    }
+
+    /// Behave like a backend that cannot prefill after cached positions
+    pub fn without_offset_prefill(mut self) -> Self {
+        self.offset_prefill = false;
+        self
+    }
+
+    /// Shared log of the forward calls, still readable after the model is moved
+    pub fn calls(&self) -> ForwardCalls {
+        self.calls.clone()
+    }
+}
+
+impl CausalLM for ScriptedModel {
+    fn forward(
+        &mut self,
+        input: &Tensor,
+        index_pos: usize,
+    ) -> candle_transformers::models::mimi::candle::Result<Tensor> {
+        let (_, seq_len) = input.dims2()?;
+        let mut calls = self.calls.lock().unwrap();
+        let token = self
+            .script
+            .get(calls.len())
+            .or(self.script.last())
+            .copied()
+            .unwrap_or(0);
+        calls.push((index_pos, seq_len));
+        let mut logits = vec![0f32; self.vocab_size];
+        logits[token as usize] = 20.0;
+        Tensor::from_vec(logits, (1, self.vocab_size), input.device())
+    }
+
+    fn clear_kv_cache(&mut self) {}
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        4096
+    }
+
+    fn supports_offset_prefill(&self) -> bool {
+        self.offset_prefill
+    }
+}
+
+/// Greedy, penalty-free generation over a `word_tokenizer` of `pieces`; `script` lists
+/// the pieces the model predicts
+pub fn scripted_generation(pieces: &[&str], script: &[&str]) -> (TextGeneration, ForwardCalls) {
+    scripted_generation_with(pieces, script, |model| model)
+}
+
+/// Same as `scripted_generation`, with `configure` applied to the model
+pub fn scripted_generation_with(
+    pieces: &[&str],
+    script: &[&str],
+    configure: impl FnOnce(ScriptedModel) -> ScriptedModel,
+) -> (TextGeneration, ForwardCalls) {
+    let tokenizer = word_tokenizer(pieces);
+    let script = script
+        .iter()
+        .map(|piece| tokenizer.token_to_id(piece).unwrap())
+        .collect();
+    let model = configure(ScriptedModel::new(script, tokenizer.get_vocab_size(true)));
+    let calls = model.calls();
+    let generation = TextGeneration::builder(model, tokenizer, &Device::Cpu)
+        .sampling(SamplingConfig::Greedy)
+        .repeat_penalty(1.0)
+        .build()
+        .unwrap();
+    (generation, calls)
+}
@@ -5,6 +5,10 @@ pub struct TokenOutputStream {
     tokens: Vec<u32>,
     prev_index: usize,
     current_index: usize,
+    stop_sequences: Vec<String>,
+    /// Decoded text held back because it may be the beginning of a stop sequence
+    held: String,
+    stopped: bool,
 }
 
 impl TokenOutputStream {
@@ -14,6 +18,9 @@ impl TokenOutputStream {
             tokens: Vec::new(),
             prev_index: 0,
             current_index: 0,
+            stop_sequences: Vec::new(),
+            held: String::new(),
+            stopped: false,
         }
     }
 
@@ -37,17 +44,19 @@ impl TokenOutputStream {
         };
         self.tokens.push(token);
         let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() && text.chars().last().unwrap().is_alphanumeric() {
+        // Hold back only an incomplete multi-byte character, so that every other piece of
+        // text, punctuation included, goes through the stop sequence matcher right away
+        if text.len() > prev_text.len() && !text.ends_with(char::REPLACEMENT_CHARACTER) {
             let text = text.split_at(prev_text.len());
             self.prev_index = self.current_index;
             self.current_index = self.tokens.len();
-            Ok(Some(text.1.to_string()))
+            Ok(self.check_stop(text.1))
         } else {
             Ok(None)
         }
     }
 
-    pub fn decode_rest(&self) -> Result<Option<String>> {
+    pub fn decode_rest(&mut self) -> Result<Option<String>> {
         let prev_text = if self.tokens.is_empty() {
             String::new()
         } else {
@@ -55,12 +64,72 @@ impl TokenOutputStream {
             self.decode(tokens)?
         };
         let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() {
+        let rest = if text.len() > prev_text.len() {
             let text = text.split_at(prev_text.len());
-            Ok(Some(text.1.to_string()))
+            self.check_stop(text.1)
         } else {
-            Ok(None)
+            None
+        };
+        // Nothing else is coming, so a partial stop sequence is just text
+        let held = std::mem::take(&mut self.held);
+        let rest = match rest {
+            Some(rest) => held + &rest,
+            None => held,
+        };
+        Ok(Some(rest).filter(|rest| !rest.is_empty()))
+    }
+
+    /// Stop generating text once any of `stop_sequences` appears, even across token boundaries
+    pub fn set_stop_sequences(&mut self, stop_sequences: Vec<String>) {
+        self.stop_sequences = stop_sequences;
+        self.stop_sequences.retain(|stop| !stop.is_empty());
+    }
+
+    /// Whether a stop sequence has been matched; the match and anything after it is dropped
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Pass `text` through the stop sequence matcher, returning what is safe to emit
+    fn check_stop(&mut self, text: &str) -> Option<String> {
+        if self.stopped {
+            return None;
         }
+        if self.stop_sequences.is_empty() {
+            return Some(text.to_string());
+        }
+        self.held.push_str(text);
+
+        let matched = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop| self.held.find(stop.as_str()))
+            .min();
+        let emit_len = match matched {
+            Some(pos) => {
+                self.stopped = true;
+                pos
+            }
+            None => self.held.len() - self.partial_stop_len(),
+        };
+        let emit: String = self.held.drain(..emit_len).collect();
+        if self.stopped {
+            self.held.clear();
+        }
+        Some(emit).filter(|emit| !emit.is_empty())
+    }
+
+    /// Length of the longest suffix of the held text that starts some stop sequence
+    fn partial_stop_len(&self) -> usize {
+        self.stop_sequences
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len().min(self.held.len() + 1))
+                    .rev()
+                    .find(|&n| stop.is_char_boundary(n) && self.held.ends_with(&stop[..n]))
+            })
+            .max()
+            .unwrap_or(0)
     }
 
     pub fn _decode_all(&self) -> Result<String> {
@@ -75,9 +144,85 @@ impl TokenOutputStream {
         &self.tokenizer
     }
 
-    pub fn _clear(&mut self) {
+    pub fn clear(&mut self) {
         self.tokens.clear();
         self.prev_index = 0;
         self.current_index = 0;
+        self.held.clear();
+        self.stopped = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::word_tokenizer;
+
+    /// Feed `pieces` one token each and collect the emitted text
+    fn run(pieces: &[&str], stop_sequences: &[&str]) -> (String, bool, usize) {
+        let tokenizer = word_tokenizer(pieces);
+        let mut stream = TokenOutputStream::new(tokenizer.clone());
+        stream.set_stop_sequences(stop_sequences.iter().map(|s| s.to_string()).collect());
+        let mut text = String::new();
+        let mut consumed = 0;
+        for piece in pieces {
+            let token = tokenizer.token_to_id(piece).unwrap();
+            consumed += 1;
+            if let Some(chunk) = stream.next_token(token).unwrap() {
+                text.push_str(&chunk);
+            }
+            if stream.stopped() {
+                break;
+            }
+        }
+        if let Some(rest) = stream.decode_rest().unwrap() {
+            text.push_str(&rest);
+        }
+        (text, stream.stopped(), consumed)
+    }
+
+    #[test]
+    fn stop_sequence_split_across_tokens() {
+        let (text, stopped, consumed) = run(&["Hello", " Us", "er", ":", " bye"], &["User:"]);
+        assert_eq!(text, "Hello ");
+        assert!(stopped);
+        assert_eq!(consumed, 4);
+    }
+
+    #[test]
+    fn partial_match_is_released() {
+        let (text, stopped, _) = run(&["Us", "ually", " fine"], &["User:"]);
+        assert_eq!(text, "Usually fine");
+        assert!(!stopped);
+    }
+
+    #[test]
+    fn partial_match_at_the_end_is_text() {
+        let (text, stopped, _) = run(&["a", " Us"], &["User:"]);
+        assert_eq!(text, "a Us");
+        assert!(!stopped);
+    }
+
+    #[test]
+    fn punctuation_only_stop_sequence() {
+        let (text, stopped, consumed) = run(&["ok", " ", "```", "\n", "\n", "```"], &["```"]);
+        assert_eq!(text, "ok ");
+        assert!(stopped);
+        assert_eq!(consumed, 3);
+    }
+
+    #[test]
+    fn stop_sequence_starting_with_newline() {
+        let (text, stopped, consumed) = run(&["Hi", "\n", "User", ":", "m"], &["\nUser:"]);
+        assert_eq!(text, "Hi");
+        assert!(stopped);
+        assert_eq!(consumed, 4);
+    }
+
+    #[test]
+    fn no_stop_sequences_passes_text_through() {
+        let (text, stopped, _) = run(&["a", ",", " b", "!"], &[]);
+        assert_eq!(text, "a, b!");
+        assert!(!stopped);
     }
 }